@@ -30,7 +30,8 @@ class WebSocketService {
 
     const wsUrl = (import.meta.env.VITE_WS_URL || 'ws://localhost:3000').replace('http', 'ws') + '/ws';
 
-    this.socket = new WebSocket(wsUrl);
+    // Browsers can't set headers on the upgrade, so the token goes in a subprotocol
+    this.socket = new WebSocket(wsUrl, ['rusty-chat', `bearer.${token}`]);
     this.setupEventListeners();
     this.isConnecting = false;
   }
@@ -42,12 +43,6 @@ class WebSocketService {
       console.log('WebSocket connected');
       this.reconnectAttempts = 0;
       this.emit('connected', null);
-
-      // Authenticate after connection
-      const token = localStorage.getItem('access_token');
-      if (token) {
-        this.authenticate(token);
-      }
     };
 
     this.socket.onclose = (event) => {
@@ -182,7 +177,7 @@ class WebSocketService {
 
   public authenticate(token: string) {
     if (this.socket && this.socket.readyState === WebSocket.OPEN) {
-      this.sendWebSocketMessage('authenticate', { token });
+      this.sendWebSocketMessage('auth', { token, protocol_version: 2 });
     } else {
       localStorage.setItem('access_token', token);
       this.connect();
@@ -0,0 +1,105 @@
+-- Create custom types
+DO $$ BEGIN
+    CREATE TYPE conversation_kind AS ENUM ('direct', 'group');
+EXCEPTION
+    WHEN duplicate_object THEN null;
+END $$;
+
+-- Conversations table
+-- Group conversations share their id with the group so existing clients can keep
+-- using the group id as chat_id. Direct conversations are keyed by the ordered
+-- pair of participant ids so there is at most one per pair of users.
+CREATE TABLE IF NOT EXISTS conversations (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    kind conversation_kind NOT NULL,
+    group_id UUID UNIQUE REFERENCES groups(id) ON DELETE CASCADE,
+    direct_key VARCHAR(73) UNIQUE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    CHECK (
+        (kind = 'group' AND group_id IS NOT NULL AND direct_key IS NULL) OR
+        (kind = 'direct' AND group_id IS NULL AND direct_key IS NOT NULL)
+    )
+);
+
+-- Conversation participants table
+CREATE TABLE IF NOT EXISTS conversation_participants (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    conversation_id UUID NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    joined_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    UNIQUE(conversation_id, user_id)
+);
+
+-- Backfill group conversations
+INSERT INTO conversations (id, kind, group_id, created_at)
+SELECT id, 'group', id, created_at FROM groups
+ON CONFLICT DO NOTHING;
+
+INSERT INTO conversation_participants (conversation_id, user_id, joined_at)
+SELECT group_id, user_id, joined_at FROM group_members
+ON CONFLICT DO NOTHING;
+
+-- Backfill direct conversations. Legacy direct messages stored the other user's id
+-- in chat_id, so every (sender_id, chat_id) pair that is not a group is a DM.
+CREATE TEMPORARY TABLE legacy_direct_pairs AS
+SELECT DISTINCT
+    LEAST(m.sender_id, m.chat_id) AS user_a,
+    GREATEST(m.sender_id, m.chat_id) AS user_b
+FROM messages m
+JOIN users u ON u.id = m.chat_id
+WHERE NOT EXISTS (SELECT 1 FROM groups g WHERE g.id = m.chat_id);
+
+INSERT INTO conversations (kind, direct_key, created_at)
+SELECT 'direct', user_a::text || ':' || user_b::text, NOW()
+FROM legacy_direct_pairs
+ON CONFLICT DO NOTHING;
+
+INSERT INTO conversation_participants (conversation_id, user_id)
+SELECT c.id, p.user_a
+FROM legacy_direct_pairs p
+JOIN conversations c ON c.direct_key = p.user_a::text || ':' || p.user_b::text
+UNION
+SELECT c.id, p.user_b
+FROM legacy_direct_pairs p
+JOIN conversations c ON c.direct_key = p.user_a::text || ':' || p.user_b::text
+ON CONFLICT DO NOTHING;
+
+UPDATE messages m
+SET chat_id = c.id
+FROM conversations c
+WHERE c.kind = 'direct'
+  AND NOT EXISTS (SELECT 1 FROM groups g WHERE g.id = m.chat_id)
+  AND c.direct_key = LEAST(m.sender_id, m.chat_id)::text || ':' || GREATEST(m.sender_id, m.chat_id)::text;
+
+DROP TABLE legacy_direct_pairs;
+
+-- Messages whose chat_id matches neither a group nor a user were never deliverable.
+-- They are set aside rather than deleted, so they can still be inspected or recovered.
+CREATE TABLE IF NOT EXISTS orphaned_messages (LIKE messages);
+
+INSERT INTO orphaned_messages
+SELECT * FROM messages WHERE chat_id NOT IN (SELECT id FROM conversations);
+
+DELETE FROM messages WHERE chat_id NOT IN (SELECT id FROM conversations);
+
+DO $$ BEGIN
+    ALTER TABLE messages ADD CONSTRAINT messages_chat_id_fkey
+        FOREIGN KEY (chat_id) REFERENCES conversations(id) ON DELETE CASCADE;
+EXCEPTION
+    WHEN duplicate_object THEN null;
+END $$;
+
+COMMENT ON COLUMN messages.chat_id IS 'References conversations(id)';
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_conversation_participants_conversation_id ON conversation_participants(conversation_id);
+CREATE INDEX IF NOT EXISTS idx_conversation_participants_user_id ON conversation_participants(user_id);
+
+-- Create triggers for updated_at
+DO $$ BEGIN
+    CREATE TRIGGER update_conversations_updated_at BEFORE UPDATE ON conversations
+        FOR EACH ROW EXECUTE FUNCTION update_updated_at_column();
+EXCEPTION
+    WHEN duplicate_object THEN null;
+END $$;
@@ -0,0 +1,17 @@
+-- Per-participant chat state
+ALTER TABLE conversation_participants ADD COLUMN IF NOT EXISTS is_muted BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE conversation_participants ADD COLUMN IF NOT EXISTS is_pinned BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE conversation_participants ADD COLUMN IF NOT EXISTS last_read_message_id UUID REFERENCES messages(id) ON DELETE SET NULL;
+
+-- Treat everything sent before this migration as read
+UPDATE conversation_participants cp
+SET last_read_message_id = (
+    SELECT m.id FROM messages m
+    WHERE m.chat_id = cp.conversation_id
+    ORDER BY m.created_at DESC
+    LIMIT 1
+)
+WHERE cp.last_read_message_id IS NULL;
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_messages_chat_id_created_at ON messages(chat_id, created_at);
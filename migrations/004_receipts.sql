@@ -0,0 +1,7 @@
+-- Delivery cursor per participant, alongside the read cursor
+ALTER TABLE conversation_participants ADD COLUMN IF NOT EXISTS last_delivered_message_id UUID REFERENCES messages(id) ON DELETE SET NULL;
+
+-- Anything already read has been delivered
+UPDATE conversation_participants
+SET last_delivered_message_id = last_read_message_id
+WHERE last_delivered_message_id IS NULL;
@@ -0,0 +1,14 @@
+-- Retracted messages are kept as tombstones
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP WITH TIME ZONE;
+
+-- Messages a user deleted for themselves only
+CREATE TABLE IF NOT EXISTS hidden_messages (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    hidden_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    UNIQUE(message_id, user_id)
+);
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_hidden_messages_user_id ON hidden_messages(user_id);
@@ -0,0 +1,15 @@
+-- Message reactions, either a unicode emoji or a custom emoji id
+CREATE TABLE IF NOT EXISTS message_reactions (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    emoji VARCHAR(64),
+    custom_emoji_id UUID,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    CHECK ((emoji IS NULL) <> (custom_emoji_id IS NULL))
+);
+
+-- Create indexes
+CREATE UNIQUE INDEX IF NOT EXISTS idx_message_reactions_unique
+    ON message_reactions(message_id, user_id, COALESCE(emoji, custom_emoji_id::text));
+CREATE INDEX IF NOT EXISTS idx_message_reactions_message_id ON message_reactions(message_id);
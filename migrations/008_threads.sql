@@ -0,0 +1,33 @@
+-- Top-level message a reply belongs to, so nested replies share one thread
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS thread_root_id UUID REFERENCES messages(id) ON DELETE SET NULL;
+
+WITH RECURSIVE thread_roots AS (
+    SELECT id, id AS root_id FROM messages WHERE reply_to IS NULL
+    UNION ALL
+    SELECT m.id, t.root_id FROM messages m JOIN thread_roots t ON m.reply_to = t.id
+)
+UPDATE messages m
+SET thread_root_id = t.root_id
+FROM thread_roots t
+WHERE m.id = t.id AND m.reply_to IS NOT NULL AND m.thread_root_id IS NULL;
+
+-- Users following a thread
+CREATE TABLE IF NOT EXISTS thread_subscriptions (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    thread_root_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    UNIQUE(thread_root_id, user_id)
+);
+
+-- Thread starters and repliers follow their threads
+INSERT INTO thread_subscriptions (thread_root_id, user_id)
+SELECT DISTINCT m.thread_root_id, m.sender_id FROM messages m WHERE m.thread_root_id IS NOT NULL
+UNION
+SELECT DISTINCT r.id, r.sender_id FROM messages r
+WHERE EXISTS (SELECT 1 FROM messages m WHERE m.thread_root_id = r.id)
+ON CONFLICT DO NOTHING;
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_messages_thread_root_id ON messages(thread_root_id);
+CREATE INDEX IF NOT EXISTS idx_thread_subscriptions_user_id ON thread_subscriptions(user_id);
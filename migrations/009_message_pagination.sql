@@ -0,0 +1,3 @@
+-- Keyset pagination over a chat's history orders by (created_at, id)
+CREATE INDEX IF NOT EXISTS idx_messages_chat_id_created_at_id ON messages(chat_id, created_at, id);
+DROP INDEX IF EXISTS idx_messages_chat_id_created_at;
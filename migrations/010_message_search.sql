@@ -0,0 +1,26 @@
+-- Full-text search over message content. The 'simple' configuration avoids
+-- language-specific stemming since chats are not in a single language.
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS search_vector TSVECTOR;
+
+CREATE OR REPLACE FUNCTION update_message_search_vector()
+RETURNS TRIGGER AS $$
+BEGIN
+    NEW.search_vector = to_tsvector('simple', COALESCE(NEW.content, ''));
+    RETURN NEW;
+END;
+$$ language 'plpgsql';
+
+DO $$ BEGIN
+    CREATE TRIGGER update_messages_search_vector BEFORE INSERT OR UPDATE OF content ON messages
+        FOR EACH ROW EXECUTE FUNCTION update_message_search_vector();
+EXCEPTION
+    WHEN duplicate_object THEN null;
+END $$;
+
+-- Backfill without bumping updated_at, which would replay every message to syncing clients
+ALTER TABLE messages DISABLE TRIGGER update_messages_updated_at;
+UPDATE messages SET search_vector = to_tsvector('simple', COALESCE(content, '')) WHERE search_vector IS NULL;
+ALTER TABLE messages ENABLE TRIGGER update_messages_updated_at;
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_messages_search_vector ON messages USING GIN (search_vector);
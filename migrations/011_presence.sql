@@ -0,0 +1,15 @@
+-- Presence chosen by the user. Whether they are connected at all is still tracked by
+-- users.is_online; 'invisible' users are shown as offline to everyone else.
+DO $$ BEGIN
+    CREATE TYPE presence_status AS ENUM ('online', 'idle', 'away', 'dnd', 'invisible');
+EXCEPTION
+    WHEN duplicate_object THEN null;
+END $$;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS presence presence_status NOT NULL DEFAULT 'online';
+ALTER TABLE users ADD COLUMN IF NOT EXISTS status_text VARCHAR(128);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS status_expires_at TIMESTAMP WITH TIME ZONE;
+
+-- Used by the presence sweeper
+CREATE INDEX IF NOT EXISTS idx_users_is_online ON users(id) WHERE is_online;
+CREATE INDEX IF NOT EXISTS idx_users_status_expires_at ON users(status_expires_at) WHERE status_expires_at IS NOT NULL;
@@ -0,0 +1,3 @@
+-- Hex SHA-256 of the file content, computed while it is uploaded. NULL for files
+-- uploaded before hashes were recorded.
+ALTER TABLE files ADD COLUMN IF NOT EXISTS content_hash CHAR(64);
@@ -0,0 +1,23 @@
+-- Files are kept by a storage backend under a key instead of at a path on local disk.
+-- Existing files are on local disk, named after their filename inside UPLOAD_DIR.
+ALTER TABLE files ADD COLUMN IF NOT EXISTS storage_backend VARCHAR(16) NOT NULL DEFAULT 'local';
+ALTER TABLE files ADD COLUMN IF NOT EXISTS storage_key VARCHAR(255);
+UPDATE files SET storage_key = filename WHERE storage_key IS NULL;
+ALTER TABLE files ALTER COLUMN storage_key SET NOT NULL;
+ALTER TABLE files DROP COLUMN IF EXISTS file_path;
+
+-- Uploads sent by clients straight to object storage with a presigned URL. The file is
+-- recorded once the client reports the upload complete.
+CREATE TABLE IF NOT EXISTS presigned_uploads (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    uploader_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    filename VARCHAR(255) NOT NULL,
+    file_type VARCHAR(100) NOT NULL,
+    file_size BIGINT NOT NULL,
+    storage_backend VARCHAR(16) NOT NULL,
+    storage_key VARCHAR(255) NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_presigned_uploads_expires_at ON presigned_uploads(expires_at);
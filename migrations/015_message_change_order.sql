@@ -0,0 +1,42 @@
+-- Sync follows changes to messages in the order they become visible. updated_at is the
+-- start time of the changing transaction, so a slow transaction can commit a change
+-- behind a cursor a client already holds. Every change instead records the id of its
+-- transaction and a sequence number; transaction ids below the oldest one still running
+-- can't gain new rows, so sync only reads up to there.
+CREATE SEQUENCE IF NOT EXISTS message_change_seq;
+
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS change_xid xid8;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS change_seq BIGINT;
+
+-- Existing rows all sort before any new change, in their previous order. Backfilled
+-- without bumping updated_at, which is still the time of each message's last change.
+ALTER TABLE messages DISABLE TRIGGER update_messages_updated_at;
+UPDATE messages m
+SET change_xid = '0', change_seq = ordered.seq
+FROM (
+    SELECT id, nextval('message_change_seq') AS seq
+    FROM (SELECT id FROM messages ORDER BY updated_at, id) sorted
+) ordered
+WHERE m.id = ordered.id;
+ALTER TABLE messages ENABLE TRIGGER update_messages_updated_at;
+
+ALTER TABLE messages ALTER COLUMN change_xid SET NOT NULL;
+ALTER TABLE messages ALTER COLUMN change_seq SET NOT NULL;
+
+CREATE OR REPLACE FUNCTION record_message_change()
+RETURNS TRIGGER AS $$
+BEGIN
+    NEW.change_xid = pg_current_xact_id();
+    NEW.change_seq = nextval('message_change_seq');
+    RETURN NEW;
+END;
+$$ language 'plpgsql';
+
+DO $$ BEGIN
+    CREATE TRIGGER record_messages_change BEFORE INSERT OR UPDATE ON messages
+        FOR EACH ROW EXECUTE FUNCTION record_message_change();
+EXCEPTION
+    WHEN duplicate_object THEN null;
+END $$;
+
+CREATE INDEX IF NOT EXISTS idx_messages_change_xid_seq ON messages(change_xid, change_seq);
@@ -6,7 +6,9 @@ use axum::{
 use serde_json::{json, Value};
 
 use crate::{
+    handlers::AuthenticatedUser,
     models::{AuthResponse, LoginRequest, RefreshTokenRequest, RegisterRequest},
+    services::websocket::TICKET_TTL_SECS,
     AppState,
 };
 
@@ -48,3 +50,17 @@ pub async fn refresh_token(
         )),
     }
 }
+
+/// Issues a short-lived ticket for authenticating a WebSocket upgrade with `/ws?ticket=`.
+pub async fn create_ws_ticket(
+    State(state): State<AppState>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    match state.services.websocket.issue_ticket(user_id).await {
+        Ok(ticket) => Ok(Json(json!({ "ticket": ticket, "expires_in": TICKET_TTL_SECS }))),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": e.to_string() })),
+        )),
+    }
+}
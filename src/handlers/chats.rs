@@ -0,0 +1,38 @@
+use axum::{
+    extract::{Path, Request, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    handlers::{messages::message_error, AuthenticatedUser, extract_user_id, convert_auth_error},
+    models::{ChatResponse, UpdateChatSettingsRequest},
+    AppState,
+};
+
+pub async fn get_chats(
+    State(state): State<AppState>,
+    request: Request,
+) -> Result<Json<Vec<ChatResponse>>, (StatusCode, Json<Value>)> {
+    let user_id = extract_user_id(&request).map_err(convert_auth_error)?;
+
+    match state.services.message.get_chats(user_id).await {
+        Ok(chats) => Ok(Json(chats)),
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn update_chat_settings(
+    State(state): State<AppState>,
+    Path(chat_id): Path<Uuid>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Json(req): Json<UpdateChatSettingsRequest>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+
+    match state.services.message.update_chat_settings(user_id, chat_id, req).await {
+        Ok(_) => Ok(Json(json!({ "message": "Chat settings updated" }))),
+        Err(e) => Err(message_error(e)),
+    }
+}
@@ -0,0 +1,268 @@
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json, Response,
+    },
+};
+use futures_util::stream::{self, Stream};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    convert::Infallible,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    handlers::AuthenticatedUser,
+    models::{Authenticated, PollResponse, Resumed, ServerEvent, PROTOCOL_VERSION},
+    services::{
+        outbound::{Outbound, OutboundQueue},
+        websocket::{encode, ConnectionId},
+    },
+    websocket::{authenticate_credentials, bearer_token, disconnect, fan_out_presence, register_connection},
+    AppState,
+};
+
+const DEFAULT_POLL_TIMEOUT_SECS: u64 = 25;
+/// Longest a poll waits for events, below common proxy timeouts.
+const MAX_POLL_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Deserialize)]
+pub struct EventStreamQuery {
+    ticket: Option<String>,
+    last_seq: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct PollQuery {
+    connection_id: Option<Uuid>,
+    last_seq: Option<i64>,
+    ack: Option<u64>,
+    timeout: Option<u64>,
+}
+
+/// A connection opened for an SSE or long-polling client, with the frames to send before
+/// its events.
+struct EventConnection {
+    id: ConnectionId,
+    queue: OutboundQueue,
+    frames: Vec<String>,
+}
+
+/// Unregisters an SSE connection once its stream is dropped, i.e. the client went away.
+struct StreamGuard {
+    state: AppState,
+    user_id: Uuid,
+    connection_id: ConnectionId,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let (state, user_id, connection_id) = (self.state.clone(), self.user_id, self.connection_id);
+        tokio::spawn(async move { disconnect(&state, user_id, connection_id).await });
+    }
+}
+
+/// Streams the user's events as Server-Sent Events, for clients behind proxies that break
+/// WebSockets. Authenticates with a bearer token or, since `EventSource` can't set headers,
+/// a ticket from `/api/ws/ticket`.
+pub async fn stream_events(
+    State(state): State<AppState>,
+    Query(query): Query<EventStreamQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let user_id = match authenticate_credentials(&state, bearer_token(&headers), query.ticket.as_deref()).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return StatusCode::UNAUTHORIZED.into_response(),
+        Err(status) => return status.into_response(),
+    };
+
+    // EventSource sends the id of the last event it received when it reconnects
+    let last_seq = headers
+        .get("last-event-id")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.parse().ok())
+        .or(query.last_seq);
+
+    let connection = match open_connection(&state, user_id, last_seq).await {
+        Ok(connection) => connection,
+        Err(e) => return internal_error(e).into_response(),
+    };
+
+    let keep_alive = KeepAlive::new().interval(Duration::from_secs(state.config.ws_ping_interval));
+    Sse::new(event_stream(state, user_id, connection)).keep_alive(keep_alive).into_response()
+}
+
+/// Long-polls for the user's events. The first poll opens a connection that keeps
+/// collecting events between polls for as long as they come within `WS_PING_TIMEOUT`.
+/// Pass its `connection_id` to each poll, along with `last_seq`, so a connection that
+/// expired or is held by another node is replaced without missing events, and the
+/// `cursor` of the last response as `ack`, so a response that got lost is sent again.
+pub async fn poll_events(
+    State(state): State<AppState>,
+    Query(query): Query<PollQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+) -> Result<Json<PollResponse>, (StatusCode, Json<Value>)> {
+    let timeout = Duration::from_secs(query.timeout.unwrap_or(DEFAULT_POLL_TIMEOUT_SECS).min(MAX_POLL_TIMEOUT_SECS));
+    // The lease runs until WS_PING_TIMEOUT after this poll ends
+    let expires_at = Instant::now() + timeout + Duration::from_secs(state.config.ws_ping_timeout);
+
+    let held = query.connection_id.and_then(|connection_id| {
+        let (queue, resend) = state.services.websocket.poll_queue(user_id, connection_id, expires_at, query.ack)?;
+        Some((connection_id, queue, resend))
+    });
+    // A lost response is sent again on its own, so responses don't grow while they keep failing
+    let resending = held.as_ref().is_some_and(|(_, _, resend)| !resend.is_empty());
+    let (connection_id, queue, mut frames) = match held {
+        Some(held) => held,
+        None => {
+            let connection = open_connection(&state, user_id, query.last_seq).await.map_err(internal_error)?;
+            state.services.websocket.hold_poll(user_id, connection.id, connection.queue.clone(), expires_at);
+            (connection.id, connection.queue, connection.frames)
+        }
+    };
+
+    // Wait for an event unless there's something to return already, then take the rest
+    let mut closed = false;
+    if frames.is_empty() {
+        match tokio::time::timeout(timeout, next_frame(&queue)).await {
+            Ok(Some(frame)) => frames.push(frame),
+            Ok(None) => closed = true,
+            Err(_) => {}
+        }
+    }
+    while !closed && !resending {
+        match queue.try_next() {
+            Some(Outbound::Frame(frame)) => frames.push(frame),
+            Some(Outbound::Ping) => {}
+            Some(Outbound::Close(..)) => closed = true,
+            None => break,
+        }
+    }
+
+    // The client fell behind; its next poll opens a new connection that replays from last_seq
+    let cursor = if closed {
+        state.services.websocket.release_poll(connection_id);
+        disconnect(&state, user_id, connection_id).await;
+        0
+    } else {
+        state.services.websocket.hand_out_poll(connection_id, &frames)
+    };
+
+    let events = frames.iter().filter_map(|frame| serde_json::from_str(frame).ok()).collect();
+    Ok(Json(PollResponse { connection_id, cursor, events }))
+}
+
+/// Registers a queue for the user's events. It's preceded by `authenticated` and, given
+/// `last_seq`, the events missed since then and `resumed`, just like `resume` on `/ws`.
+async fn open_connection(state: &AppState, user_id: Uuid, last_seq: Option<i64>) -> anyhow::Result<EventConnection> {
+    let queue = state.services.websocket.new_queue();
+    let connection = register_connection(state, user_id, queue.clone()).await?;
+
+    let frames = match initial_frames(state, user_id, connection.id, last_seq).await {
+        Ok(frames) => frames,
+        Err(e) => {
+            disconnect(state, user_id, connection.id).await;
+            return Err(e);
+        }
+    };
+
+    if connection.first {
+        if let Err(e) = fan_out_presence(state, user_id).await {
+            warn!("Failed to send presence of user {}: {}", user_id, e);
+        }
+    }
+
+    Ok(EventConnection {
+        id: connection.id,
+        queue,
+        frames,
+    })
+}
+
+async fn initial_frames(
+    state: &AppState,
+    user_id: Uuid,
+    connection_id: ConnectionId,
+    last_seq: Option<i64>,
+) -> anyhow::Result<Vec<String>> {
+    let mut frames = vec![encode(ServerEvent::Authenticated(Authenticated {
+        user_id,
+        connection_id,
+        protocol_version: PROTOCOL_VERSION,
+        last_seq: state.services.websocket.current_seq(user_id).await?,
+    }))?];
+
+    if let Some(last_seq) = last_seq {
+        let replay = state.services.websocket.replay(user_id, last_seq).await?;
+
+        // A partial replay would leave gaps, so the client resyncs instead
+        let replayed = if replay.complete { replay.frames.len() } else { 0 };
+        if replay.complete {
+            frames.extend(replay.frames);
+        }
+        frames.push(encode(ServerEvent::Resumed(Resumed {
+            replayed,
+            current_seq: replay.current_seq,
+            resync_required: !replay.complete,
+        }))?);
+    }
+
+    Ok(frames)
+}
+
+fn event_stream(
+    state: AppState,
+    user_id: Uuid,
+    connection: EventConnection,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let guard = StreamGuard {
+        state,
+        user_id,
+        connection_id: connection.id,
+    };
+
+    stream::unfold(
+        (guard, connection.queue, connection.frames.into_iter()),
+        |(guard, queue, mut initial)| async move {
+            let frame = match initial.next() {
+                Some(frame) => frame,
+                None => next_frame(&queue).await?,
+            };
+            Some((Ok(sse_event(frame)), (guard, queue, initial)))
+        },
+    )
+}
+
+/// Waits for the next frame. Returns `None` once the queue is closed, e.g. because the
+/// client fell behind.
+async fn next_frame(queue: &OutboundQueue) -> Option<String> {
+    loop {
+        match queue.next().await? {
+            Outbound::Frame(frame) => return Some(frame),
+            Outbound::Ping => {}
+            Outbound::Close(..) => return None,
+        }
+    }
+}
+
+// Names the event after its message_type and, for sequenced events, uses seq as its id
+fn sse_event(frame: String) -> Event {
+    let value: Value = serde_json::from_str(&frame).unwrap_or_default();
+    let mut event = Event::default().data(frame);
+    if let Some(message_type) = value.get("message_type").and_then(Value::as_str) {
+        event = event.event(message_type);
+    }
+    if let Some(seq) = value.get("seq").and_then(Value::as_i64) {
+        event = event.id(seq.to_string());
+    }
+    event
+}
+
+fn internal_error(err: anyhow::Error) -> (StatusCode, Json<Value>) {
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() })))
+}
@@ -1,48 +1,90 @@
 use axum::{
-    extract::{Path, State},
-    http::{header, StatusCode},
-    response::{IntoResponse, Response},
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
     Json,
 };
-use axum_extra::extract::Multipart;
+use axum_extra::extract::{multipart::MultipartError, Multipart};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
 use serde_json::{json, Value};
 use uuid::Uuid;
 
 use crate::{
-    handlers::AuthenticatedUser,
-    models::FileResponse,
+    handlers::{convert_auth_error, AuthenticatedUser},
+    models::{File, FileResponse, PresignUploadRequest, PresignedUploadResponse},
+    services::file::FileError,
+    websocket::bearer_token,
     AppState,
 };
 
+/// Allowance for multipart boundaries, headers and small fields on top of the file itself.
+pub const MULTIPART_OVERHEAD: usize = 64 * 1024;
+
+/// Files never change once stored, so clients may cache them for as long as they like.
+const FILE_CACHE_CONTROL: &str = "private, max-age=31536000, immutable";
+
+/// Types browsers display without running anything, so they're shown inline rather than
+/// downloaded. Notably excludes HTML, SVG and PDF.
+const INLINE_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "audio/mpeg",
+    "audio/ogg",
+    "audio/wav",
+    "audio/webm",
+    "audio/aac",
+    "audio/flac",
+    "audio/mp4",
+    "video/mp4",
+    "video/webm",
+    "video/ogg",
+];
+
+/// The signature of a signed download URL.
+#[derive(Deserialize)]
+pub struct SignedUrlQuery {
+    expires: Option<i64>,
+    signature: Option<String>,
+}
+
+/// What a `Range` header asks for. Anything but a single byte range is served in full.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial { start: u64, end: u64 },
+    Unsatisfiable,
+}
+
 pub async fn upload_file(
     State(state): State<AppState>,
     AuthenticatedUser(user_id): AuthenticatedUser,
     mut multipart: Multipart,
 ) -> Result<Json<FileResponse>, (StatusCode, Json<Value>)> {
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap_or("").to_string();
-        
-        if name == "file" {
-            let filename = field.file_name().unwrap_or("unknown").to_string();
-            let data = field.bytes().await.unwrap();
-
-            // Check file size
-            if data.len() > state.config.max_file_size {
-                return Err((
-                    StatusCode::PAYLOAD_TOO_LARGE,
-                    Json(json!({ "error": "File too large" })),
-                ));
-            }
-
-            match state.services.file.save_file(user_id, &filename, &data).await {
-                Ok(file_response) => return Ok(Json(file_response)),
-                Err(e) => return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({ "error": e.to_string() })),
-                )),
-            }
+    let multipart_error = |err: MultipartError| multipart_error(err, state.config.max_file_size);
+
+    while let Some(mut field) = multipart.next_field().await.map_err(multipart_error)? {
+        if field.name() != Some("file") {
+            continue;
+        }
+
+        let filename = field.file_name().unwrap_or("unknown").to_string();
+
+        // Written to disk chunk by chunk; stops as soon as the file is too large
+        let mut upload = state.services.file.begin_upload().await.map_err(file_error)?;
+        while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+            upload.write(&chunk).await.map_err(file_error)?;
         }
+
+        return match state.services.file.finish_upload(upload, user_id, &filename).await {
+            Ok(file_response) => Ok(Json(file_response)),
+            Err(e) => Err(file_error(e)),
+        };
     }
 
     Err((
@@ -51,23 +93,367 @@ pub async fn upload_file(
     ))
 }
 
+/// Streams a file, or the byte range asked for with `Range`. Answers conditional requests
+/// with 304 when the client's copy is current. Requires a signed URL or a bearer token of
+/// the uploader or a member of a chat the file was shared in.
 pub async fn download_file(
     State(state): State<AppState>,
     Path(file_id): Path<Uuid>,
+    Query(query): Query<SignedUrlQuery>,
+    request_headers: HeaderMap,
 ) -> Result<Response, (StatusCode, Json<Value>)> {
-    match state.services.file.get_file(file_id).await {
-        Ok((file, content)) => {
-            let headers = [
-                (header::CONTENT_TYPE, file.file_type.as_str()),
-                (header::CONTENT_DISPOSITION, &format!("attachment; filename=\"{}\"", file.original_filename)),
-                (header::CONTENT_LENGTH, &content.len().to_string()),
-            ];
-
-            Ok((headers, content).into_response())
+    authorize_download(&state, file_id, &query, &request_headers).await?;
+
+    let file = state.services.file.get_file(file_id).await.map_err(file_error)?;
+    let length = file.file_size as u64;
+
+    let etag = file_etag(&file);
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, header_value(&etag));
+    headers.insert(header::LAST_MODIFIED, header_value(&http_date(file.created_at)));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(FILE_CACHE_CONTROL));
+
+    if is_not_modified(&request_headers, &etag, file.created_at) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    // Object storage can serve the file itself, ranges and all
+    let disposition = content_disposition(&file);
+    if let Some(url) = state.services.file.presigned_download(&file, &disposition).map_err(file_error)? {
+        let mut response = Redirect::temporary(&url).into_response();
+        response
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        return Ok(response);
+    }
+
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::CONTENT_TYPE, header_value(&file.file_type));
+    headers.insert(header::CONTENT_DISPOSITION, header_value(&disposition));
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+
+    // A stale If-Range gets the whole file rather than a piece of a different version
+    let range = match request_headers.get(header::RANGE).and_then(|range| range.to_str().ok()) {
+        Some(range) if if_range_matches(&request_headers, &etag, file.created_at) => byte_range(range, length),
+        _ => ByteRange::Full,
+    };
+
+    match range {
+        ByteRange::Full => {
+            let content = state.services.file.open_file(&file, None).await.map_err(file_error)?;
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+            Ok((headers, Body::from_stream(content)).into_response())
+        }
+        ByteRange::Partial { start, end } => {
+            let content = state
+                .services
+                .file
+                .open_file(&file, Some((start, end)))
+                .await
+                .map_err(file_error)?;
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start + 1));
+            headers.insert(
+                header::CONTENT_RANGE,
+                header_value(&format!("bytes {}-{}/{}", start, end, length)),
+            );
+            Ok((StatusCode::PARTIAL_CONTENT, headers, Body::from_stream(content)).into_response())
         }
-        Err(_) => Err((
-            StatusCode::NOT_FOUND,
-            Json(json!({ "error": "File not found" })),
-        )),
+        ByteRange::Unsatisfiable => {
+            headers.insert(header::CONTENT_RANGE, header_value(&format!("bytes */{}", length)));
+            Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response())
+        }
+    }
+}
+
+/// Gives the client a URL to upload a file to object storage directly. Once the upload is
+/// done, `complete_presigned_upload` records the file.
+pub async fn presign_upload(
+    State(state): State<AppState>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Json(request): Json<PresignUploadRequest>,
+) -> Result<Json<PresignedUploadResponse>, (StatusCode, Json<Value>)> {
+    let upload = state
+        .services
+        .file
+        .presign_upload(user_id, request)
+        .await
+        .map_err(file_error)?;
+
+    Ok(Json(upload))
+}
+
+pub async fn complete_presigned_upload(
+    State(state): State<AppState>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Path(upload_id): Path<Uuid>,
+) -> Result<Json<FileResponse>, (StatusCode, Json<Value>)> {
+    let file = state
+        .services
+        .file
+        .complete_presigned_upload(upload_id, user_id)
+        .await
+        .map_err(file_error)?;
+
+    Ok(Json(file))
+}
+
+// A signed URL is enough on its own; otherwise the bearer has to be allowed to see the file.
+// Files the user can't see are reported as missing
+async fn authorize_download(
+    state: &AppState,
+    file_id: Uuid,
+    query: &SignedUrlQuery,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    if let (Some(expires), Some(signature)) = (query.expires, query.signature.as_deref()) {
+        return state
+            .services
+            .file_urls
+            .verify(file_id, expires, signature)
+            .map_err(|e| file_error(e.into()));
+    }
+
+    let user_id = bearer_token(headers)
+        .and_then(|token| state.services.auth.verify_access_token(token).ok())
+        .ok_or_else(|| convert_auth_error(StatusCode::UNAUTHORIZED))?;
+
+    if !state.services.message.can_access_file(user_id, file_id).await.map_err(file_error)? {
+        return Err(file_error(FileError::NotFound.into()));
+    }
+    Ok(())
+}
+
+/// Formats a time as an HTTP-date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.trim()).ok().map(|time| time.with_timezone(&Utc))
+}
+
+// A strong validator: the content hash, or the id for files without one (stored before
+// hashing or uploaded with a presigned URL), which is just as good since files never change.
+// Presigned uploads are copied into place, so the upload URL can't change them either.
+fn file_etag(file: &File) -> String {
+    match &file.content_hash {
+        Some(hash) => format!("\"{}\"", hash),
+        None => format!("\"{}\"", file.id),
+    }
+}
+
+// If-None-Match takes precedence over If-Modified-Since and compares weakly
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: DateTime<Utc>) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+        return if_none_match.split(',').map(str::trim).any(|tag| {
+            tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag
+        });
+    }
+
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|since| since.to_str().ok())
+        .and_then(parse_http_date)
+        .is_some_and(|since| modified.timestamp() <= since.timestamp())
+}
+
+// If-Range needs an exact match of the strong ETag or of Last-Modified
+fn if_range_matches(headers: &HeaderMap, etag: &str, modified: DateTime<Utc>) -> bool {
+    let Some(if_range) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let Ok(if_range) = if_range.to_str() else {
+        return false;
+    };
+
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') {
+        return if_range == etag;
+    }
+    parse_http_date(if_range).is_some_and(|time| time.timestamp() == modified.timestamp())
+}
+
+// Parses `bytes=<start>-<end>`, `bytes=<start>-` and `bytes=-<suffix length>`
+fn byte_range(range: &str, length: u64) -> ByteRange {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (length.saturating_sub(suffix), length.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => (start, length.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+    };
+
+    if length == 0 || start >= length {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial { start, end }
+}
+
+// Safe media is shown in place; everything else is downloaded under its original name
+fn content_disposition(file: &File) -> String {
+    let disposition = if INLINE_TYPES.contains(&file.file_type.as_str()) {
+        "inline"
+    } else {
+        "attachment"
+    };
+
+    // A plain ASCII fallback, plus the exact name percent-encoded for clients that read it
+    let fallback: String = file
+        .original_filename
+        .chars()
+        .map(|c| if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let encoded: String = file
+        .original_filename
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", disposition, fallback, encoded)
+}
+
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+// Malformed bodies are a 400, bodies over the request limit a 413
+fn multipart_error(err: MultipartError, max_file_size: usize) -> (StatusCode, Json<Value>) {
+    match err.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => file_error(FileError::TooLarge(max_file_size).into()),
+        status => (status, Json(json!({ "error": err.body_text() }))),
+    }
+}
+
+// Maps file errors to 4xx responses and everything else to a server error
+pub fn file_error(err: anyhow::Error) -> (StatusCode, Json<Value>) {
+    match err.downcast_ref::<FileError>() {
+        Some(file_err) => (
+            file_error_status(file_err),
+            Json(json!({ "error": err.to_string(), "code": file_err.code() })),
+        ),
+        None => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))),
+    }
+}
+
+pub fn file_error_status(err: &FileError) -> StatusCode {
+    match err {
+        FileError::NotFound => StatusCode::NOT_FOUND,
+        FileError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        FileError::UploadNotFound => StatusCode::NOT_FOUND,
+        FileError::OffsetMismatch(_) | FileError::UploadIncomplete(_) => StatusCode::CONFLICT,
+        FileError::UploadBusy => StatusCode::LOCKED,
+        FileError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        FileError::InvalidSignature | FileError::UrlExpired => StatusCode::FORBIDDEN,
+        FileError::PresignUnsupported => StatusCode::NOT_IMPLEMENTED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const ETAG: &str = "\"abc\"";
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    fn modified() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn byte_range_bounded() {
+        assert_eq!(byte_range("bytes=0-9", 100), ByteRange::Partial { start: 0, end: 9 });
+        assert_eq!(byte_range(" bytes= 10 - 19 ", 100), ByteRange::Partial { start: 10, end: 19 });
+        assert_eq!(byte_range("bytes=99-99", 100), ByteRange::Partial { start: 99, end: 99 });
+    }
+
+    #[test]
+    fn byte_range_open_ended() {
+        assert_eq!(byte_range("bytes=90-", 100), ByteRange::Partial { start: 90, end: 99 });
+        assert_eq!(byte_range("bytes=0-", 1), ByteRange::Partial { start: 0, end: 0 });
+    }
+
+    #[test]
+    fn byte_range_suffix() {
+        assert_eq!(byte_range("bytes=-10", 100), ByteRange::Partial { start: 90, end: 99 });
+        // A suffix longer than the file is the whole file
+        assert_eq!(byte_range("bytes=-500", 100), ByteRange::Partial { start: 0, end: 99 });
+        assert_eq!(byte_range("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=-5", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn byte_range_past_the_end() {
+        // The end is clamped to the last byte
+        assert_eq!(byte_range("bytes=50-1000", 100), ByteRange::Partial { start: 50, end: 99 });
+        assert_eq!(byte_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=100-200", 100), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn byte_range_served_in_full() {
+        assert_eq!(byte_range("bytes=0-9,20-29", 100), ByteRange::Full);
+        assert_eq!(byte_range("bytes=9-0", 100), ByteRange::Full);
+        assert_eq!(byte_range("bytes=a-b", 100), ByteRange::Full);
+        assert_eq!(byte_range("bytes=5", 100), ByteRange::Full);
+        assert_eq!(byte_range("items=0-9", 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn not_modified_by_etag() {
+        let headers = |value| headers(header::IF_NONE_MATCH, value);
+        assert!(is_not_modified(&headers("\"abc\""), ETAG, modified()));
+        assert!(is_not_modified(&headers("W/\"abc\""), ETAG, modified()));
+        assert!(is_not_modified(&headers("\"xyz\", W/\"abc\""), ETAG, modified()));
+        assert!(is_not_modified(&headers("*"), ETAG, modified()));
+        assert!(!is_not_modified(&headers("\"xyz\""), ETAG, modified()));
+        assert!(!is_not_modified(&headers("abc"), ETAG, modified()));
+    }
+
+    #[test]
+    fn etag_takes_precedence_over_date() {
+        let mut headers = headers(header::IF_NONE_MATCH, "\"xyz\"");
+        headers.insert(header::IF_MODIFIED_SINCE, "Wed, 01 May 2024 12:00:00 GMT".parse().unwrap());
+        assert!(!is_not_modified(&headers, ETAG, modified()));
+    }
+
+    #[test]
+    fn not_modified_by_date() {
+        let headers = |value| headers(header::IF_MODIFIED_SINCE, value);
+        assert!(is_not_modified(&headers("Wed, 01 May 2024 12:00:00 GMT"), ETAG, modified()));
+        assert!(is_not_modified(&headers("Thu, 02 May 2024 00:00:00 GMT"), ETAG, modified()));
+        assert!(!is_not_modified(&headers("Wed, 01 May 2024 11:59:59 GMT"), ETAG, modified()));
+        assert!(!is_not_modified(&headers("yesterday"), ETAG, modified()));
+        assert!(!is_not_modified(&HeaderMap::new(), ETAG, modified()));
     }
 }
@@ -8,15 +8,37 @@ use serde_json::{json, Value};
 use uuid::Uuid;
 
 use crate::{
-    handlers::{extract_user_id, convert_auth_error},
-    models::MessageResponse,
+    handlers::{AuthenticatedUser, extract_user_id, convert_auth_error},
+    models::{
+        EditMessageRequest, MessageEdit, MessageEdited, MessageReactionChanged, MessageResponse, MessageSearchQuery,
+        MessageSearchResult, ReactionRequest, SendMessageRequest, ServerEvent, SyncResponse, ThreadResponse,
+    },
+    services::{
+        message::{MessageError, MessagePage},
+        rate_limit::FrameClass,
+    },
+    websocket::fan_out_message,
     AppState,
 };
 
 #[derive(Deserialize)]
 pub struct MessageQuery {
     limit: Option<i64>,
-    offset: Option<i64>,
+    before: Option<Uuid>,
+    after: Option<Uuid>,
+    around: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct SyncQuery {
+    since: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteMessageQuery {
+    #[serde(default)]
+    for_everyone: bool,
 }
 
 pub async fn get_messages(
@@ -25,16 +47,248 @@ pub async fn get_messages(
     Query(query): Query<MessageQuery>,
     request: Request,
 ) -> Result<Json<Vec<MessageResponse>>, (StatusCode, Json<Value>)> {
-    let _user_id = extract_user_id(&request).map_err(convert_auth_error)?;
+    let user_id = extract_user_id(&request).map_err(convert_auth_error)?;
     
-    let limit = query.limit.unwrap_or(50).min(100);
-    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(50).clamp(1, 100);
+    let page = match (query.before, query.after, query.around) {
+        (None, None, None) => MessagePage::Latest,
+        (Some(id), None, None) => MessagePage::Before(id),
+        (None, Some(id), None) => MessagePage::After(id),
+        (None, None, Some(id)) => MessagePage::Around(id),
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Only one of before, after and around may be given" })),
+            ))
+        }
+    };
 
-    match state.services.message.get_messages(chat_id, limit, offset).await {
+    match state.services.message.get_messages(user_id, chat_id, page, limit).await {
         Ok(messages) => Ok(Json(messages)),
-        Err(e) => Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": e.to_string() })),
-        )),
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+/// Sends a message, for clients receiving events over SSE or long polling. It's delivered
+/// exactly like one sent over `/ws` and counts against the same per-user rate limit.
+pub async fn send_message(
+    State(state): State<AppState>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Json(req): Json<SendMessageRequest>,
+) -> Result<Json<MessageResponse>, (StatusCode, Json<Value>)> {
+    if let Err(retry_after) = state.services.rate_limit.check_user(user_id, FrameClass::SendMessage) {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({
+                "error": "Too many messages",
+                "code": "rate_limited",
+                "retry_after_ms": retry_after.as_millis().max(1) as u64,
+            })),
+        ));
+    }
+
+    match state.services.message.send_message(user_id, req).await {
+        Ok(message) => {
+            if let Err(e) = fan_out_message(&state, &message).await {
+                tracing::warn!("Failed to deliver message {}: {}", message.id, e);
+            }
+            Ok(Json(message))
+        }
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn sync_messages(
+    State(state): State<AppState>,
+    Query(query): Query<SyncQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+) -> Result<Json<SyncResponse>, (StatusCode, Json<Value>)> {
+    let limit = query.limit.unwrap_or(100).clamp(1, 500);
+
+    match state.services.message.sync_messages(user_id, query.since.as_deref(), limit).await {
+        Ok(sync) => Ok(Json(sync)),
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn search_messages(
+    State(state): State<AppState>,
+    Query(query): Query<MessageSearchQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+) -> Result<Json<Vec<MessageSearchResult>>, (StatusCode, Json<Value>)> {
+
+    match state.services.message.search_messages(user_id, query).await {
+        Ok(results) => Ok(Json(results)),
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn edit_message(
+    State(state): State<AppState>,
+    Path(message_id): Path<Uuid>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Json(req): Json<EditMessageRequest>,
+) -> Result<Json<MessageResponse>, (StatusCode, Json<Value>)> {
+
+    match state.services.message.edit_message(user_id, message_id, req).await {
+        Ok(message) => {
+            if let Ok(participants) = state.services.message.get_chat_participants(message.chat_id).await {
+                let _ = state.services.websocket.broadcast(ServerEvent::MessageEdited(&MessageEdited::from(&message)), &participants).await;
+            }
+            Ok(Json(message))
+        }
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn delete_message(
+    State(state): State<AppState>,
+    Path(message_id): Path<Uuid>,
+    Query(query): Query<DeleteMessageQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+
+    match state.services.message.delete_message(user_id, message_id, query.for_everyone).await {
+        Ok((deleted, released_file)) => {
+            if let Some(file_id) = released_file {
+                if let Err(e) = state.services.file.release_file(file_id).await {
+                    tracing::warn!("Failed to release file {}: {}", file_id, e);
+                }
+            }
+
+            let recipients = if deleted.for_everyone {
+                state.services.message.get_chat_participants(deleted.chat_id).await.unwrap_or_default()
+            } else {
+                vec![user_id]
+            };
+            let _ = state.services.websocket.broadcast(ServerEvent::MessageDeleted(&deleted), &recipients).await;
+
+            Ok(Json(json!({ "message": "Message deleted" })))
+        }
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn get_message_edits(
+    State(state): State<AppState>,
+    Path(message_id): Path<Uuid>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+) -> Result<Json<Vec<MessageEdit>>, (StatusCode, Json<Value>)> {
+
+    match state.services.message.get_message_edits(user_id, message_id).await {
+        Ok(edits) => Ok(Json(edits)),
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn get_thread(
+    State(state): State<AppState>,
+    Path(message_id): Path<Uuid>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+) -> Result<Json<ThreadResponse>, (StatusCode, Json<Value>)> {
+
+    match state.services.message.get_thread(user_id, message_id).await {
+        Ok(thread) => Ok(Json(thread)),
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn subscribe_thread(
+    State(state): State<AppState>,
+    Path(message_id): Path<Uuid>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+
+    match state.services.message.set_thread_subscription(user_id, message_id, true).await {
+        Ok(()) => Ok(Json(json!({ "message": "Subscribed to thread" }))),
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn unsubscribe_thread(
+    State(state): State<AppState>,
+    Path(message_id): Path<Uuid>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+
+    match state.services.message.set_thread_subscription(user_id, message_id, false).await {
+        Ok(()) => Ok(Json(json!({ "message": "Unsubscribed from thread" }))),
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn add_reaction(
+    State(state): State<AppState>,
+    Path(message_id): Path<Uuid>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Json(req): Json<ReactionRequest>,
+) -> Result<Json<MessageReactionChanged>, (StatusCode, Json<Value>)> {
+
+    match state.services.message.add_reaction(user_id, message_id, req).await {
+        Ok(reaction) => {
+            if let Ok(participants) = state.services.message.get_chat_participants(reaction.chat_id).await {
+                let _ = state.services.websocket.broadcast(ServerEvent::MessageReaction(&reaction), &participants).await;
+            }
+            Ok(Json(reaction))
+        }
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn remove_reaction(
+    State(state): State<AppState>,
+    Path(message_id): Path<Uuid>,
+    Query(req): Query<ReactionRequest>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+) -> Result<Json<MessageReactionChanged>, (StatusCode, Json<Value>)> {
+
+    match state.services.message.remove_reaction(user_id, message_id, req).await {
+        Ok(reaction) => {
+            if let Ok(participants) = state.services.message.get_chat_participants(reaction.chat_id).await {
+                let _ = state.services.websocket.broadcast(ServerEvent::MessageReaction(&reaction), &participants).await;
+            }
+            Ok(Json(reaction))
+        }
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+pub async fn mark_read(
+    State(state): State<AppState>,
+    Path(message_id): Path<Uuid>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+
+    match state.services.message.mark_read(user_id, message_id).await {
+        Ok(Some(receipt)) => {
+            if let Ok(participants) = state.services.message.get_chat_participants(receipt.chat_id).await {
+                let _ = state.services.websocket.broadcast_receipt(true, &receipt, &participants, None).await;
+            }
+            Ok(Json(json!({ "message": "Marked as read" })))
+        }
+        Ok(None) => Ok(Json(json!({ "message": "Marked as read" }))),
+        Err(e) => Err(message_error(e)),
+    }
+}
+
+// Maps message errors to 4xx responses and everything else to a server error
+pub fn message_error(err: anyhow::Error) -> (StatusCode, Json<Value>) {
+    match err.downcast_ref::<MessageError>() {
+        Some(message_err) => (
+            message_error_status(message_err),
+            Json(json!({ "error": err.to_string(), "code": message_err.code() })),
+        ),
+        None => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))),
+    }
+}
+
+pub fn message_error_status(err: &MessageError) -> StatusCode {
+    match err {
+        MessageError::NotFound | MessageError::MessageNotFound => StatusCode::NOT_FOUND,
+        MessageError::NotMember
+        | MessageError::Blocked
+        | MessageError::NotSender
+        | MessageError::RetractWindowExpired => StatusCode::FORBIDDEN,
+        MessageError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
     }
 }
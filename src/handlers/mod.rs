@@ -4,6 +4,9 @@ pub mod messages;
 pub mod friends;
 pub mod groups;
 pub mod files;
+pub mod chats;
+pub mod events;
+pub mod uploads;
 
 use axum::{
     extract::{Request, State},
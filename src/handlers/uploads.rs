@@ -0,0 +1,235 @@
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    handlers::{
+        files::{file_error, file_error_status, http_date},
+        AuthenticatedUser,
+    },
+    models::{FileResponse, Upload},
+    services::file::FileError,
+    AppState,
+};
+
+/// The only version of the tus protocol spoken here.
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,creation-with-upload,termination,expiration";
+/// Content type of the bytes sent with PATCH and creation-with-upload.
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const TUS_VERSION_HEADER: HeaderName = HeaderName::from_static("tus-version");
+const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+const TUS_MAX_SIZE: HeaderName = HeaderName::from_static("tus-max-size");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+
+/// Speaks the tus protocol around the upload routes: adds the server's capabilities to
+/// `OPTIONS` responses, rejects other requests for unsupported protocol versions and marks
+/// every response with `Tus-Resumable`. Runs outside CORS, which answers `OPTIONS`, and
+/// authentication, so `OPTIONS` is public.
+pub async fn tus_middleware(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let mut response = if request.method() == Method::OPTIONS {
+        let mut response = next.run(request).await;
+        if response.status().is_success() {
+            *response.status_mut() = StatusCode::NO_CONTENT;
+            let headers = response.headers_mut();
+            headers.insert(TUS_VERSION_HEADER, HeaderValue::from_static(TUS_VERSION));
+            headers.insert(TUS_EXTENSION, HeaderValue::from_static(TUS_EXTENSIONS));
+            headers.insert(TUS_MAX_SIZE, HeaderValue::from(state.config.max_file_size));
+        }
+        response
+    } else if request.method() != Method::GET
+        && request.headers().get(&TUS_RESUMABLE).map(HeaderValue::as_bytes) != Some(TUS_VERSION.as_bytes())
+    {
+        (
+            StatusCode::PRECONDITION_FAILED,
+            [(TUS_VERSION_HEADER, TUS_VERSION)],
+            Json(json!({ "error": "Unsupported tus version", "code": "unsupported_version" })),
+        )
+            .into_response()
+    } else {
+        next.run(request).await
+    };
+
+    response
+        .headers_mut()
+        .insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+/// Starts a resumable upload of `Upload-Length` bytes. The file name comes from the
+/// `filename` key of `Upload-Metadata`. The request may carry the first bytes already.
+pub async fn create_upload(
+    State(state): State<AppState>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, (StatusCode, Json<Value>)> {
+    let length = header_i64(&headers, &UPLOAD_LENGTH)
+        .ok_or_else(|| invalid_request("Upload-Length is required"))?;
+    let filename = headers
+        .get(&UPLOAD_METADATA)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|metadata| metadata_value(metadata, "filename"))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let file = &state.services.file;
+    let mut upload = file.create_upload(user_id, &filename, length).await.map_err(file_error)?;
+
+    // creation-with-upload
+    if is_offset_octet_stream(&headers) && upload.file_id.is_none() {
+        match file.append_upload(upload.id, user_id, 0, body.into_data_stream()).await {
+            Ok(appended) => upload = appended,
+            // The upload exists regardless; the client resumes from the offset reached
+            Err(e) => {
+                tracing::warn!("Failed to append to new upload {}: {}", upload.id, e);
+                if let Ok(current) = file.get_upload(upload.id, user_id).await {
+                    upload = current;
+                }
+            }
+        }
+    }
+
+    let mut response = (StatusCode::CREATED, upload_headers(&upload)).into_response();
+    response.headers_mut().insert(
+        header::LOCATION,
+        HeaderValue::from_str(&format!("/api/uploads/{}", upload.id)).unwrap(),
+    );
+    Ok(response)
+}
+
+/// Reports how far an upload has got, so the client knows where to resume.
+pub async fn get_upload_offset(
+    State(state): State<AppState>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Path(upload_id): Path<Uuid>,
+) -> Response {
+    match state.services.file.get_upload(upload_id, user_id).await {
+        Ok(upload) => {
+            let mut response = (StatusCode::OK, upload_headers(&upload)).into_response();
+            response.headers_mut().insert(
+                UPLOAD_LENGTH,
+                HeaderValue::from(upload.upload_length),
+            );
+            response
+                .headers_mut()
+                .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+            response
+        }
+        // HEAD responses have no body
+        Err(e) => status_only(e),
+    }
+}
+
+/// Appends the request body to an upload at `Upload-Offset`, which must match the upload's
+/// current offset.
+pub async fn append_upload(
+    State(state): State<AppState>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Path(upload_id): Path<Uuid>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, (StatusCode, Json<Value>)> {
+    if !is_offset_octet_stream(&headers) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(json!({ "error": format!("Content-Type must be {}", OFFSET_OCTET_STREAM) })),
+        ));
+    }
+    let offset = header_i64(&headers, &UPLOAD_OFFSET)
+        .ok_or_else(|| invalid_request("Upload-Offset is required"))?;
+
+    let upload = state
+        .services
+        .file
+        .append_upload(upload_id, user_id, offset, body.into_data_stream())
+        .await
+        .map_err(file_error)?;
+
+    Ok((StatusCode::NO_CONTENT, upload_headers(&upload)).into_response())
+}
+
+/// Cancels an upload and discards what was received so far.
+pub async fn delete_upload(
+    State(state): State<AppState>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Path(upload_id): Path<Uuid>,
+) -> Result<StatusCode, (StatusCode, Json<Value>)> {
+    state
+        .services
+        .file
+        .delete_upload(upload_id, user_id)
+        .await
+        .map_err(file_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Returns the file a completed upload produced, for attaching it to messages.
+pub async fn get_upload_file(
+    State(state): State<AppState>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Path(upload_id): Path<Uuid>,
+) -> Result<Json<FileResponse>, (StatusCode, Json<Value>)> {
+    let file = state
+        .services
+        .file
+        .get_upload_file(upload_id, user_id)
+        .await
+        .map_err(file_error)?;
+
+    Ok(Json(file))
+}
+
+fn upload_headers(upload: &Upload) -> [(HeaderName, String); 2] {
+    [
+        (UPLOAD_OFFSET, upload.upload_offset.to_string()),
+        (UPLOAD_EXPIRES, http_date(upload.expires_at)),
+    ]
+}
+
+fn header_i64(headers: &HeaderMap, name: &HeaderName) -> Option<i64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+fn is_offset_octet_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|header| header.to_str().ok())
+        .is_some_and(|content_type| content_type.eq_ignore_ascii_case(OFFSET_OCTET_STREAM))
+}
+
+// Upload-Metadata is a comma-separated list of keys, each followed by its base64 value
+fn metadata_value(metadata: &str, key: &str) -> Option<String> {
+    metadata.split(',').find_map(|pair| {
+        let mut parts = pair.trim().splitn(2, ' ');
+        if parts.next()? != key {
+            return None;
+        }
+        let value = STANDARD.decode(parts.next()?.trim()).ok()?;
+        String::from_utf8(value).ok()
+    })
+}
+
+fn invalid_request(message: &str) -> (StatusCode, Json<Value>) {
+    file_error(FileError::InvalidRequest(message.to_string()).into())
+}
+
+fn status_only(err: anyhow::Error) -> Response {
+    match err.downcast_ref::<FileError>() {
+        Some(file_err) => file_error_status(file_err).into_response(),
+        None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
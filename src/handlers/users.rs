@@ -7,8 +7,10 @@ use serde::Deserialize;
 use serde_json::{json, Value};
 
 use crate::{
-    handlers::{extract_user_id, convert_auth_error},
-    models::UserResponse,
+    handlers::{extract_user_id, convert_auth_error, AuthenticatedUser},
+    models::{UpdatePresenceRequest, UserResponse, UserStatus},
+    services::user::UserError,
+    websocket::fan_out_presence,
     AppState,
 };
 
@@ -47,3 +49,35 @@ pub async fn search_users(
         )),
     }
 }
+
+/// Sets the user's presence or custom status and notifies everyone who can see it.
+pub async fn update_presence(
+    State(state): State<AppState>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Json(req): Json<UpdatePresenceRequest>,
+) -> Result<Json<UserStatus>, (StatusCode, Json<Value>)> {
+    if let Err(e) = state.services.user.update_presence(user_id, req).await {
+        return Err(user_error(e));
+    }
+
+    match fan_out_presence(&state, user_id).await {
+        Ok(status) => Ok(Json(status)),
+        Err(e) => Err(user_error(e)),
+    }
+}
+
+fn user_error(err: anyhow::Error) -> (StatusCode, Json<Value>) {
+    match err.downcast_ref::<UserError>() {
+        Some(user_err) => (
+            user_error_status(user_err),
+            Json(json!({ "error": err.to_string(), "code": user_err.code() })),
+        ),
+        None => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))),
+    }
+}
+
+pub fn user_error_status(err: &UserError) -> StatusCode {
+    match err {
+        UserError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+    }
+}
@@ -1,10 +1,12 @@
 use axum::{
-    extract::{ws::WebSocketUpgrade, State},
+    extract::{ws::WebSocketUpgrade, DefaultBodyLimit, Query, State},
+    http::{header, HeaderMap},
     middleware,
-    response::Response,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Router,
 };
+use std::time::Duration;
 use tower_http::{cors::CorsLayer, services::ServeDir};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -15,9 +17,12 @@ mod models;
 mod services;
 mod websocket;
 
+/// How often expired resumable uploads are cleaned up.
+const UPLOAD_SWEEP_SECS: u64 = 300;
+
 use config::Config;
 use database::Database;
-use services::AppServices;
+use services::{storage::StorageKind, AppServices};
 
 #[derive(Clone)]
 pub struct AppState {
@@ -42,12 +47,32 @@ async fn main() -> anyhow::Result<()> {
     let db = Database::new(&config.database_url).await?;
     let services = AppServices::new(db.clone(), &config).await?;
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("migrate-storage") {
+        return migrate_storage(&services, &args[1..]).await;
+    }
+
     let state = AppState {
         db,
         services,
         config: config.clone(),
     };
 
+    websocket::spawn_cluster_tasks(state.clone());
+    spawn_upload_sweeper(state.clone());
+
+    // Metrics get their own listener so they aren't exposed with the public API
+    if let Some(metrics_addr) = &config.metrics_addr {
+        let listener = tokio::net::TcpListener::bind(metrics_addr).await?;
+        tracing::info!("Serving metrics on {}", metrics_addr);
+        let metrics_app = Router::new().route("/metrics", get(metrics)).with_state(state.clone());
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, metrics_app).await {
+                tracing::error!("Metrics server failed: {}", e);
+            }
+        });
+    }
+
     let app = create_router(state);
 
     let listener = tokio::net::TcpListener::bind(&config.server_addr).await?;
@@ -65,12 +90,15 @@ fn create_router(state: AppState) -> Router {
         .route("/api/auth/register", post(handlers::auth::register))
         .route("/api/auth/login", post(handlers::auth::login))
         .route("/api/auth/refresh", post(handlers::auth::refresh_token))
-        .route("/api/files/:id", get(handlers::files::download_file));
+        .route("/api/files/:id", get(handlers::files::download_file))
+        // Authenticates itself, since EventSource can only pass a ticket
+        .route("/api/events", get(handlers::events::stream_events));
 
     // Protected routes (auth required)
     let protected_routes = Router::new()
         .route("/api/users/me", get(handlers::users::get_current_user))
         .route("/api/users/search", get(handlers::users::search_users))
+        .route("/api/users/me/presence", axum::routing::put(handlers::users::update_presence))
         .route("/api/friends", get(handlers::friends::get_friends))
         .route("/api/friends/requests", post(handlers::friends::send_friend_request))
         .route("/api/friends/requests/:id/accept", post(handlers::friends::accept_friend_request))
@@ -81,20 +109,104 @@ fn create_router(state: AppState) -> Router {
         .route("/api/groups/:id/members", post(handlers::groups::add_member))
         .route("/api/groups/:id/members/:user_id", axum::routing::delete(handlers::groups::remove_member))
         .route("/api/groups/:id/members", get(handlers::groups::get_group_members))
-        .route("/api/messages/:chat_id", get(handlers::messages::get_messages))
-        .route("/api/upload", post(handlers::files::upload_file))
+        .route("/api/chats", get(handlers::chats::get_chats))
+        .route("/api/chats/:id", axum::routing::patch(handlers::chats::update_chat_settings))
+        .route("/api/events/poll", get(handlers::events::poll_events))
+        .route("/api/messages", post(handlers::messages::send_message))
+        .route("/api/messages/:id", get(handlers::messages::get_messages))
+        .route("/api/sync", get(handlers::messages::sync_messages))
+        .route("/api/search/messages", get(handlers::messages::search_messages))
+        .route("/api/messages/:id", axum::routing::patch(handlers::messages::edit_message))
+        .route("/api/messages/:id", axum::routing::delete(handlers::messages::delete_message))
+        .route("/api/messages/:id/edits", get(handlers::messages::get_message_edits))
+        .route("/api/messages/:id/thread", get(handlers::messages::get_thread))
+        .route("/api/messages/:id/thread/subscription", post(handlers::messages::subscribe_thread))
+        .route("/api/messages/:id/thread/subscription", axum::routing::delete(handlers::messages::unsubscribe_thread))
+        .route("/api/messages/:id/reactions", post(handlers::messages::add_reaction))
+        .route("/api/messages/:id/reactions", axum::routing::delete(handlers::messages::remove_reaction))
+        .route("/api/messages/:id/read", post(handlers::messages::mark_read))
+        .route(
+            "/api/upload",
+            post(handlers::files::upload_file).layer(DefaultBodyLimit::max(
+                state.config.max_file_size + handlers::files::MULTIPART_OVERHEAD,
+            )),
+        )
+        .route("/api/files/presigned", post(handlers::files::presign_upload))
+        .route("/api/files/presigned/:id/complete", post(handlers::files::complete_presigned_upload))
+        .route("/api/ws/ticket", post(handlers::auth::create_ws_ticket))
         .layer(middleware::from_fn_with_state(state.clone(), handlers::auth_middleware));
 
+    // Resumable uploads (tus protocol)
+    let upload_routes = Router::new()
+        .route("/api/uploads", post(handlers::uploads::create_upload))
+        .route(
+            "/api/uploads/:id",
+            get(handlers::uploads::get_upload_file)
+                .head(handlers::uploads::get_upload_offset)
+                .patch(handlers::uploads::append_upload)
+                .delete(handlers::uploads::delete_upload),
+        )
+        .route_layer(middleware::from_fn_with_state(state.clone(), handlers::auth_middleware))
+        .route_layer(CorsLayer::permissive())
+        .route_layer(middleware::from_fn_with_state(state.clone(), handlers::uploads::tus_middleware));
+
     Router::new()
         .merge(public_routes)
         .merge(protected_routes)
         .route("/ws", get(websocket_handler))
-        .nest_service("/uploads", ServeDir::new("uploads"))
         .nest_service("/", ServeDir::new("frontend/dist"))
         .layer(CorsLayer::permissive())
+        // Has its own CORS layer, inside the tus middleware
+        .merge(upload_routes)
         .with_state(state)
 }
 
+/// `rusty-chat migrate-storage <from> <to> [--delete-source]` moves the contents of files
+/// between storage backends (`local` or `s3`) instead of starting the server.
+async fn migrate_storage(services: &AppServices, args: &[String]) -> anyhow::Result<()> {
+    let usage = "Usage: rusty-chat migrate-storage <local|s3> <local|s3> [--delete-source]";
+    let (from, to) = match args {
+        [from, to, ..] => (
+            from.parse::<StorageKind>().map_err(|e| anyhow::anyhow!("{}\n{}", e, usage))?,
+            to.parse::<StorageKind>().map_err(|e| anyhow::anyhow!("{}\n{}", e, usage))?,
+        ),
+        _ => return Err(anyhow::anyhow!(usage)),
+    };
+    let delete_source = args[2..].iter().any(|arg| arg == "--delete-source");
+
+    let summary = services.file.migrate_storage(from, to, delete_source).await?;
+    tracing::info!(
+        "Moved {} files from {} to {}, {} failed",
+        summary.moved,
+        from.as_str(),
+        to.as_str(),
+        summary.failed
+    );
+    if summary.failed > 0 {
+        return Err(anyhow::anyhow!("{} files could not be migrated; rerun to retry them", summary.failed));
+    }
+    Ok(())
+}
+
+fn spawn_upload_sweeper(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(UPLOAD_SWEEP_SECS));
+        loop {
+            interval.tick().await;
+
+            match state.services.websocket.try_lock("upload_sweep", UPLOAD_SWEEP_SECS - 1).await {
+                Ok(true) => match state.services.file.expire_uploads().await {
+                    Ok(0) => {}
+                    Ok(expired) => tracing::info!("Removed {} expired uploads", expired),
+                    Err(e) => tracing::warn!("Upload sweep failed: {}", e),
+                },
+                Ok(false) => {}
+                Err(e) => tracing::warn!("Failed to acquire upload sweep lock: {}", e),
+            }
+        }
+    });
+}
+
 async fn health_check() -> impl axum::response::IntoResponse {
     axum::Json(serde_json::json!({
         "status": "ok",
@@ -103,9 +215,47 @@ async fn health_check() -> impl axum::response::IntoResponse {
     }))
 }
 
+/// WebSocket queue metrics for this node in the Prometheus text format.
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let stats = state.services.websocket.queue_stats().await;
+    let body = format!(
+        "# HELP ws_connections WebSocket connections on this node.\n\
+         # TYPE ws_connections gauge\n\
+         ws_connections {}\n\
+         # HELP ws_outbound_queued_frames Frames waiting to be written across all connections.\n\
+         # TYPE ws_outbound_queued_frames gauge\n\
+         ws_outbound_queued_frames {}\n\
+         # HELP ws_outbound_queue_depth_max Frames waiting on the most backed-up connection.\n\
+         # TYPE ws_outbound_queue_depth_max gauge\n\
+         ws_outbound_queue_depth_max {}\n\
+         # HELP ws_outbound_dropped_frames_total Typing and presence frames dropped for slow clients.\n\
+         # TYPE ws_outbound_dropped_frames_total counter\n\
+         ws_outbound_dropped_frames_total {}\n\
+         # HELP ws_slow_consumer_disconnects_total Connections closed for falling too far behind.\n\
+         # TYPE ws_slow_consumer_disconnects_total counter\n\
+         ws_slow_consumer_disconnects_total {}\n",
+        stats.connections,
+        stats.queued_frames,
+        stats.max_queue_depth,
+        stats.dropped_frames,
+        stats.slow_consumer_disconnects,
+    );
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
 async fn websocket_handler(
     State(state): State<AppState>,
+    Query(query): Query<websocket::UpgradeQuery>,
+    headers: HeaderMap,
     ws: WebSocketUpgrade,
 ) -> Response {
-    ws.on_upgrade(|socket| websocket::handle_socket(socket, state))
+    match websocket::authenticate_upgrade(&state, &headers, &query).await {
+        Ok(auth) => {
+            let codec = query.codec();
+            ws.protocols([websocket::SUBPROTOCOL])
+                .on_upgrade(move |socket| websocket::handle_socket(socket, state, auth, codec))
+        }
+        Err(status) => status.into_response(),
+    }
 }
@@ -0,0 +1,71 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use super::MessageResponse;
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Conversation {
+    pub id: Uuid,
+    pub kind: ConversationKind,
+    pub group_id: Option<Uuid>,
+    pub direct_key: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "conversation_kind", rename_all = "lowercase")]
+pub enum ConversationKind {
+    Direct,
+    Group,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ConversationParticipant {
+    pub id: Uuid,
+    pub conversation_id: Uuid,
+    pub user_id: Uuid,
+    pub joined_at: DateTime<Utc>,
+    pub is_muted: bool,
+    pub is_pinned: bool,
+    pub last_read_message_id: Option<Uuid>,
+    pub last_delivered_message_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatResponse {
+    pub id: Uuid,
+    pub kind: ConversationKind,
+    pub name: String,
+    pub avatar_url: Option<String>,
+    pub peer: Option<ChatPeer>,
+    pub last_message: Option<MessageResponse>,
+    pub unread_count: i64,
+    pub is_muted: bool,
+    pub is_pinned: bool,
+    pub last_activity_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatPeer {
+    pub id: Uuid,
+    pub username: String,
+    pub avatar_url: Option<String>,
+    pub is_online: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateChatSettingsRequest {
+    pub is_muted: Option<bool>,
+    pub is_pinned: Option<bool>,
+}
+
+impl Conversation {
+    /// Key identifying the direct conversation between two users, independent of order.
+    pub fn direct_key(user_a: Uuid, user_b: Uuid) -> String {
+        let (low, high) = if user_a <= user_b { (user_a, user_b) } else { (user_b, user_a) };
+        format!("{}:{}", low, high)
+    }
+}
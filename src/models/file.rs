@@ -1,6 +1,7 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -10,9 +11,11 @@ pub struct File {
     pub original_filename: String,
     pub file_type: String,
     pub file_size: i64,
-    pub file_path: String,
     pub uploader_id: Uuid,
     pub created_at: DateTime<Utc>,
+    pub content_hash: Option<String>,
+    pub storage_backend: String,
+    pub storage_key: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -26,6 +29,52 @@ pub struct FileResponse {
     pub created_at: DateTime<Utc>,
 }
 
+/// A resumable upload. It's complete once `upload_offset` reaches `upload_length`, at
+/// which point `file_id` refers to the stored file.
+#[derive(Debug, Clone, FromRow)]
+pub struct Upload {
+    pub id: Uuid,
+    pub uploader_id: Uuid,
+    pub filename: String,
+    pub upload_length: i64,
+    pub upload_offset: i64,
+    pub temp_path: String,
+    pub file_id: Option<Uuid>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An upload a client sends straight to object storage. The file is recorded when the
+/// client reports it complete.
+#[derive(Debug, Clone, FromRow)]
+pub struct PresignedUpload {
+    pub id: Uuid,
+    pub uploader_id: Uuid,
+    pub filename: String,
+    pub file_type: String,
+    pub file_size: i64,
+    pub storage_backend: String,
+    pub storage_key: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PresignUploadRequest {
+    pub filename: String,
+    pub file_size: i64,
+    pub content_type: Option<String>,
+}
+
+/// Where and how to send the file. `headers` have to be sent exactly as given.
+#[derive(Debug, Serialize)]
+pub struct PresignedUploadResponse {
+    pub id: Uuid,
+    pub method: String,
+    pub url: String,
+    pub headers: HashMap<String, String>,
+    pub expires_at: DateTime<Utc>,
+}
+
 impl File {
     pub fn to_response(&self, base_url: &str) -> FileResponse {
         FileResponse {
@@ -12,10 +12,22 @@ pub struct Message {
     pub message_type: MessageType,
     pub file_id: Option<Uuid>,
     pub reply_to: Option<Uuid>,
+    pub thread_root_id: Option<Uuid>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct MessageEdit {
+    pub id: Uuid,
+    pub message_id: Uuid,
+    pub editor_id: Uuid,
+    pub previous_content: Option<String>,
+    pub edited_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "message_type", rename_all = "lowercase")]
 pub enum MessageType {
@@ -37,9 +49,68 @@ pub struct MessageResponse {
     pub message_type: MessageType,
     pub file: Option<MessageFile>,
     pub reply_to: Option<Uuid>,
+    pub reply_preview: Option<ReplyPreview>,
+    pub thread_root_id: Option<Uuid>,
+    pub thread: Option<ThreadSummary>,
+    pub status: MessageStatus,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub reactions: Vec<ReactionSummary>,
     pub created_at: DateTime<Utc>,
 }
 
+/// Quoted excerpt of the message being replied to. `content` is truncated and is
+/// `None` once the original has been retracted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplyPreview {
+    pub id: Uuid,
+    pub sender: MessageSender,
+    pub content: Option<String>,
+    pub message_type: MessageType,
+    pub deleted: bool,
+}
+
+/// Reply statistics on the root message of a thread.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadSummary {
+    pub reply_count: i64,
+    pub last_reply_at: Option<DateTime<Utc>>,
+    pub subscribed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadResponse {
+    pub root: MessageResponse,
+    pub replies: Vec<MessageResponse>,
+}
+
+/// Sent to chat participants who don't follow a thread when a reply is posted to it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadUpdated {
+    pub chat_id: Uuid,
+    pub thread_root_id: Uuid,
+    pub reply_count: i64,
+    pub last_reply_at: Option<DateTime<Utc>>,
+}
+
+/// Aggregated reactions of one kind on a message. `reacted_by_me` is relative to the
+/// user the message was loaded for.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReactionSummary {
+    pub emoji: Option<String>,
+    pub custom_emoji_id: Option<Uuid>,
+    pub count: i64,
+    pub reacted_by_me: bool,
+}
+
+/// Delivery state of a message as seen by its sender.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MessageStatus {
+    Sent,
+    Delivered,
+    Read,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MessageSender {
     pub id: Uuid,
@@ -65,10 +136,123 @@ pub struct SendMessageRequest {
     pub reply_to: Option<Uuid>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct EditMessageRequest {
+    pub content: String,
+}
+
+/// Payload of the `edit_message` WebSocket event.
+#[derive(Debug, Deserialize)]
+pub struct EditMessageEvent {
+    pub message_id: Uuid,
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteMessageRequest {
+    pub message_id: Uuid,
+    #[serde(default)]
+    pub for_everyone: bool,
+}
+
+/// Sent to everyone in the chat when a message is edited. Carries only what the edit
+/// changed, which is the same for every viewer; clients merge it into the message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageEdited {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub content: Option<String>,
+    pub edited_at: Option<DateTime<Utc>>,
+}
+
+impl From<&MessageResponse> for MessageEdited {
+    fn from(message: &MessageResponse) -> Self {
+        MessageEdited {
+            id: message.id,
+            chat_id: message.chat_id,
+            content: message.content.clone(),
+            edited_at: message.edited_at,
+        }
+    }
+}
+
+/// Sent when a message is retracted for everyone, or to the user's own devices when
+/// they delete it for themselves.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageDeleted {
+    pub chat_id: Uuid,
+    pub message_id: Uuid,
+    pub for_everyone: bool,
+}
+
+/// A unicode emoji or a custom emoji id; exactly one must be set.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReactionRequest {
+    pub emoji: Option<String>,
+    pub custom_emoji_id: Option<Uuid>,
+}
+
+/// Payload of the `add_reaction` and `remove_reaction` WebSocket events.
+#[derive(Debug, Deserialize)]
+pub struct ReactionEvent {
+    pub message_id: Uuid,
+    #[serde(flatten)]
+    pub reaction: ReactionRequest,
+}
+
+/// Sent to chat participants when a reaction is added or removed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageReactionChanged {
+    pub chat_id: Uuid,
+    pub message_id: Uuid,
+    pub user_id: Uuid,
+    pub emoji: Option<String>,
+    pub custom_emoji_id: Option<Uuid>,
+    pub added: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageReceiptRequest {
+    pub message_id: Uuid,
+}
+
+/// Sent to the other participants of a chat when a user receives or reads a message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageReceipt {
+    pub chat_id: Uuid,
+    pub user_id: Uuid,
+    pub message_id: Uuid,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Messages created or changed since a sync cursor, oldest change first. Pass
+/// `next_cursor` to the next sync call; `has_more` means another page is waiting.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub messages: Vec<MessageResponse>,
+    pub next_cursor: String,
+    pub has_more: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageSearchQuery {
+    pub q: String,
+    pub chat_id: Option<Uuid>,
+    pub sender_id: Option<Uuid>,
+    pub message_type: Option<MessageType>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub has_file: Option<bool>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// A search hit. `snippet` is HTML-escaped text with matched terms wrapped in `<mark>` tags.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct WebSocketMessage {
-    pub message_type: String,
-    pub data: serde_json::Value,
+pub struct MessageSearchResult {
+    pub message: MessageResponse,
+    pub snippet: String,
+    pub rank: f32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
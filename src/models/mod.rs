@@ -3,9 +3,13 @@ pub mod message;
 pub mod friend;
 pub mod group;
 pub mod file;
+pub mod conversation;
+pub mod protocol;
 
 pub use user::*;
 pub use message::*;
 pub use friend::*;
 pub use group::*;
 pub use file::*;
+pub use conversation::*;
+pub use protocol::*;
@@ -0,0 +1,196 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    DeleteMessageRequest, EditMessageEvent, MessageDeleted, MessageEdited, MessageReactionChanged, MessageReceipt,
+    MessageReceiptRequest, MessageResponse, Presence, ReactionEvent, SendMessageRequest, ThreadUpdated,
+    TypingIndicator, UpdatePresenceRequest, User,
+};
+
+/// Newest WebSocket protocol version. Version 1 is the original protocol without acks.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest protocol version still accepted at `auth`.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// A frame sent by the client. `request_id` is chosen by the client and echoed back in
+/// the `ack` or `error` frame answering it.
+#[derive(Debug, Deserialize)]
+pub struct ClientFrame {
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub request: ClientRequest,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "message_type", content = "data", rename_all = "snake_case")]
+pub enum ClientRequest {
+    Auth(AuthRequest),
+    Resume(ResumeRequest),
+    SendMessage(SendMessageRequest),
+    Typing(TypingRequest),
+    EditMessage(EditMessageEvent),
+    DeleteMessage(DeleteMessageRequest),
+    AddReaction(ReactionEvent),
+    RemoveReaction(ReactionEvent),
+    MarkRead(MessageReceiptRequest),
+    MarkDelivered(MessageReceiptRequest),
+    SetPresence(UpdatePresenceRequest),
+}
+
+impl ClientRequest {
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            ClientRequest::Auth(_) => "auth",
+            ClientRequest::Resume(_) => "resume",
+            ClientRequest::SendMessage(_) => "send_message",
+            ClientRequest::Typing(_) => "typing",
+            ClientRequest::EditMessage(_) => "edit_message",
+            ClientRequest::DeleteMessage(_) => "delete_message",
+            ClientRequest::AddReaction(_) => "add_reaction",
+            ClientRequest::RemoveReaction(_) => "remove_reaction",
+            ClientRequest::MarkRead(_) => "mark_read",
+            ClientRequest::MarkDelivered(_) => "mark_delivered",
+            ClientRequest::SetPresence(_) => "set_presence",
+        }
+    }
+}
+
+/// Version 1 clients send the bare access token.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum AuthRequest {
+    Token(String),
+    Negotiate {
+        token: String,
+        protocol_version: u32,
+    },
+}
+
+/// Asks for every event after `last_seq` that this device missed while disconnected.
+#[derive(Debug, Deserialize)]
+pub struct ResumeRequest {
+    pub last_seq: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TypingRequest {
+    pub chat_id: Uuid,
+    pub is_typing: bool,
+}
+
+/// A frame sent by the server. `request_id` is only set on `ack` and `error` frames.
+/// Events sent to all of a user's devices also carry a per-user `seq`, added when the
+/// event is logged.
+#[derive(Debug, Serialize)]
+pub struct ServerFrame<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<&'a str>,
+    #[serde(flatten)]
+    pub event: ServerEvent<'a>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "message_type", content = "data", rename_all = "snake_case")]
+pub enum ServerEvent<'a> {
+    Authenticated(Authenticated),
+    /// Result of replaying missed events to an SSE or long-polling client.
+    Resumed(Resumed),
+    Ack(serde_json::Value),
+    Error(ErrorFrame),
+    NewMessage(&'a MessageResponse),
+    MessageEdited(&'a MessageEdited),
+    MessageDeleted(&'a MessageDeleted),
+    MessageReaction(&'a MessageReactionChanged),
+    ThreadUpdated(&'a ThreadUpdated),
+    ReadReceipt(&'a MessageReceipt),
+    DeliveryReceipt(&'a MessageReceipt),
+    Typing(&'a TypingIndicator),
+    UserStatus(UserStatus),
+}
+
+impl ServerEvent<'_> {
+    /// Events that are only useful while fresh. They aren't replayed and are the first to
+    /// go when a client falls behind.
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(self, ServerEvent::Typing(_) | ServerEvent::UserStatus(_))
+    }
+}
+
+impl<'a> From<ServerEvent<'a>> for ServerFrame<'a> {
+    fn from(event: ServerEvent<'a>) -> Self {
+        ServerFrame { request_id: None, event }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Authenticated {
+    pub user_id: Uuid,
+    pub connection_id: Uuid,
+    pub protocol_version: u32,
+    pub last_seq: i64,
+}
+
+/// Answer to `resume`. When `resync_required` is set the missed events are no longer
+/// available and the client should reload its state, e.g. through `/api/sync`.
+#[derive(Debug, Serialize)]
+pub struct Resumed {
+    pub replayed: usize,
+    pub current_seq: i64,
+    pub resync_required: bool,
+}
+
+/// Answer to a long poll. `connection_id` identifies the connection to the next poll,
+/// which keeps receiving events between polls, and acknowledges these events with `cursor`.
+#[derive(Debug, Serialize)]
+pub struct PollResponse {
+    pub connection_id: Uuid,
+    pub cursor: u64,
+    pub events: Vec<serde_json::Value>,
+}
+
+/// Describes why a request failed. The connection stays open.
+#[derive(Debug, Serialize)]
+pub struct ErrorFrame {
+    pub request_type: Option<String>,
+    pub code: &'static str,
+    pub status: u16,
+    pub error: String,
+    /// Set on `rate_limited` errors: how long to wait before sending this request again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+/// A user's presence changed. `is_online` is kept for version 1 clients.
+#[derive(Debug, Clone, Serialize)]
+pub struct UserStatus {
+    pub user_id: Uuid,
+    pub is_online: bool,
+    pub presence: Presence,
+    pub status_text: Option<String>,
+    pub status_expires_at: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+impl UserStatus {
+    /// Presence as shown to other users.
+    pub fn visible(user: &User) -> Self {
+        Self::new(user, user.visible_presence(), user.visible_status_text())
+    }
+
+    /// Presence as shown on the user's own devices.
+    pub fn own(user: &User) -> Self {
+        Self::new(user, user.own_presence(), user.active_status_text())
+    }
+
+    fn new(user: &User, presence: Presence, status_text: Option<&str>) -> Self {
+        UserStatus {
+            user_id: user.id,
+            is_online: presence != Presence::Offline,
+            presence,
+            status_text: status_text.map(str::to_string),
+            status_expires_at: status_text.and(user.status_expires_at),
+            last_seen: user.last_seen,
+        }
+    }
+}
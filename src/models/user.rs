@@ -12,10 +12,88 @@ pub struct User {
     pub avatar_url: Option<String>,
     pub is_online: bool,
     pub last_seen: Option<DateTime<Utc>>,
+    pub presence: PresenceStatus,
+    pub status_text: Option<String>,
+    pub status_expires_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+impl User {
+    /// Presence as shown to other users. Invisible users appear offline.
+    pub fn visible_presence(&self) -> Presence {
+        if !self.is_online || self.presence == PresenceStatus::Invisible {
+            Presence::Offline
+        } else {
+            self.presence.into()
+        }
+    }
+
+    /// Presence as shown on the user's own devices.
+    pub fn own_presence(&self) -> Presence {
+        if self.is_online {
+            self.presence.into()
+        } else {
+            Presence::Offline
+        }
+    }
+
+    /// The custom status text, unless it has expired.
+    pub fn active_status_text(&self) -> Option<&str> {
+        match self.status_expires_at {
+            Some(expires_at) if expires_at <= Utc::now() => None,
+            _ => self.status_text.as_deref(),
+        }
+    }
+
+    /// The custom status text as shown to other users, who don't see it while the user
+    /// is invisible.
+    pub fn visible_status_text(&self) -> Option<&str> {
+        if self.presence == PresenceStatus::Invisible {
+            None
+        } else {
+            self.active_status_text()
+        }
+    }
+}
+
+/// Presence chosen by the user. `Idle` is reported by clients on inactivity and is
+/// reset to `Online` when the user reconnects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "presence_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    Online,
+    Idle,
+    Away,
+    Dnd,
+    Invisible,
+}
+
+/// Presence reported to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Presence {
+    Online,
+    Idle,
+    Away,
+    Dnd,
+    Invisible,
+    Offline,
+}
+
+impl From<PresenceStatus> for Presence {
+    fn from(status: PresenceStatus) -> Self {
+        match status {
+            PresenceStatus::Online => Presence::Online,
+            PresenceStatus::Idle => Presence::Idle,
+            PresenceStatus::Away => Presence::Away,
+            PresenceStatus::Dnd => Presence::Dnd,
+            PresenceStatus::Invisible => Presence::Invisible,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserResponse {
     pub id: Uuid,
@@ -24,23 +102,43 @@ pub struct UserResponse {
     pub avatar_url: Option<String>,
     pub is_online: bool,
     pub last_seen: Option<DateTime<Utc>>,
+    pub presence: Presence,
+    pub status_text: Option<String>,
+    pub status_expires_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
 }
 
+/// Shows presence the way other users see it.
 impl From<User> for UserResponse {
     fn from(user: User) -> Self {
+        let presence = user.visible_presence();
+        let status_text = user.visible_status_text().map(str::to_string);
+        let status_expires_at = status_text.as_ref().and(user.status_expires_at);
+
         UserResponse {
             id: user.id,
             email: user.email,
             username: user.username,
             avatar_url: user.avatar_url,
-            is_online: user.is_online,
+            is_online: presence != Presence::Offline,
             last_seen: user.last_seen,
+            presence,
+            status_text,
+            status_expires_at,
             created_at: user.created_at,
         }
     }
 }
 
+/// Changes the user's presence and custom status. Omitted fields are left unchanged;
+/// an empty `status_text` clears the custom status.
+#[derive(Debug, Deserialize)]
+pub struct UpdatePresenceRequest {
+    pub presence: Option<PresenceStatus>,
+    pub status_text: Option<String>,
+    pub status_expires_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct RegisterRequest {
     pub email: String,
@@ -83,12 +83,6 @@ impl AuthService {
             return Err(anyhow!("Invalid email or password"));
         }
 
-        // Update user online status
-        sqlx::query("UPDATE users SET is_online = true WHERE id = $1")
-            .bind(user.id)
-            .execute(self.db.pool())
-            .await?;
-
         // Generate tokens
         let access_token = self.generate_access_token(user.id)?;
         let refresh_token = self.generate_refresh_token(user.id).await?;
@@ -0,0 +1,205 @@
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression as DeflateLevel};
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::{Read, Write};
+use thiserror::Error;
+
+/// Largest frame accepted after decompression, so a small compressed frame can't expand
+/// without bound.
+const MAX_DECOMPRESSED_SIZE: u64 = 1024 * 1024;
+
+/// How a connection's frames are serialized, chosen with `?encoding=` on the upgrade.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+/// Whether each frame's payload is compressed, chosen with `?frame_compression=` on the
+/// upgrade. This is part of the app's own frame format, not the `permessage-deflate`
+/// WebSocket extension: nothing is negotiated in `Sec-WebSocket-Extensions`, and frames
+/// carry the compressed bytes as their payload.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameCompression {
+    #[default]
+    None,
+    Deflate,
+}
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid MessagePack: {0}")]
+    MsgpackDecode(#[from] rmp_serde::decode::Error),
+    #[error("Failed to encode MessagePack: {0}")]
+    MsgpackEncode(#[from] rmp_serde::encode::Error),
+    #[error("Invalid compressed frame: {0}")]
+    Compression(#[from] std::io::Error),
+    #[error("Frame exceeds {} bytes when decompressed", MAX_DECOMPRESSED_SIZE)]
+    TooLarge,
+}
+
+/// A frame ready for the socket.
+pub enum WireFrame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Converts between the JSON frames used internally and a connection's wire format.
+/// Plain JSON goes out as text frames; MessagePack and compressed frames are binary,
+/// with every frame compressed as raw deflate when compression is on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameCodec {
+    pub encoding: Encoding,
+    pub compression: FrameCompression,
+}
+
+impl FrameCodec {
+    pub fn encode(&self, frame: String) -> Result<WireFrame, CodecError> {
+        let bytes = match self.encoding {
+            Encoding::Json if self.compression == FrameCompression::None => return Ok(WireFrame::Text(frame)),
+            Encoding::Json => frame.into_bytes(),
+            Encoding::Msgpack => {
+                let value: Value = serde_json::from_str(&frame)?;
+                rmp_serde::to_vec_named(&value)?
+            }
+        };
+
+        match self.compression {
+            FrameCompression::None => Ok(WireFrame::Binary(bytes)),
+            FrameCompression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), DeflateLevel::fast());
+                encoder.write_all(&bytes)?;
+                Ok(WireFrame::Binary(encoder.finish()?))
+            }
+        }
+    }
+
+    /// Decodes a binary frame from the client. Text frames are always plain JSON.
+    pub fn decode(&self, data: &[u8]) -> Result<Value, CodecError> {
+        let decompressed;
+        let bytes = match self.compression {
+            FrameCompression::None => data,
+            FrameCompression::Deflate => {
+                let mut buf = Vec::new();
+                DeflateDecoder::new(data)
+                    .take(MAX_DECOMPRESSED_SIZE + 1)
+                    .read_to_end(&mut buf)?;
+                if buf.len() as u64 > MAX_DECOMPRESSED_SIZE {
+                    return Err(CodecError::TooLarge);
+                }
+                decompressed = buf;
+                &decompressed
+            }
+        };
+
+        match self.encoding {
+            Encoding::Json => Ok(serde_json::from_slice(bytes)?),
+            Encoding::Msgpack => Ok(rmp_serde::from_slice(bytes)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn codec(encoding: Encoding, compression: FrameCompression) -> FrameCodec {
+        FrameCodec { encoding, compression }
+    }
+
+    fn frame() -> Value {
+        json!({
+            "seq": 7,
+            "message_type": "new_message",
+            "data": { "content": "héllo", "tags": [1, 2.5, null, true] },
+        })
+    }
+
+    fn deflate(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), DeflateLevel::fast());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn binary(frame: WireFrame) -> Vec<u8> {
+        match frame {
+            WireFrame::Binary(bytes) => bytes,
+            WireFrame::Text(text) => panic!("expected a binary frame, got text {}", text),
+        }
+    }
+
+    #[test]
+    fn plain_json_is_sent_as_text() {
+        let codec = FrameCodec::default();
+        let text = frame().to_string();
+        match codec.encode(text.clone()).unwrap() {
+            WireFrame::Text(sent) => assert_eq!(sent, text),
+            WireFrame::Binary(_) => panic!("expected a text frame"),
+        }
+        assert_eq!(codec.decode(text.as_bytes()).unwrap(), frame());
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        for encoding in [Encoding::Json, Encoding::Msgpack] {
+            for compression in [FrameCompression::None, FrameCompression::Deflate] {
+                let codec = codec(encoding, compression);
+                let encoded = match codec.encode(frame().to_string()).unwrap() {
+                    WireFrame::Text(text) => text.into_bytes(),
+                    WireFrame::Binary(bytes) => bytes,
+                };
+                assert_eq!(codec.decode(&encoded).unwrap(), frame(), "{:?} {:?}", encoding, compression);
+            }
+        }
+    }
+
+    #[test]
+    fn msgpack_uses_named_fields() {
+        let bytes = binary(codec(Encoding::Msgpack, FrameCompression::None).encode(frame().to_string()).unwrap());
+        let value: Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(value, frame());
+    }
+
+    #[test]
+    fn deflate_is_raw_deflate() {
+        let text = frame().to_string();
+        let bytes = binary(codec(Encoding::Json, FrameCompression::Deflate).encode(text.clone()).unwrap());
+
+        let mut inflated = String::new();
+        DeflateDecoder::new(bytes.as_slice()).read_to_string(&mut inflated).unwrap();
+        assert_eq!(inflated, text);
+    }
+
+    #[test]
+    fn rejects_frames_too_large_when_decompressed() {
+        let codec = codec(Encoding::Json, FrameCompression::Deflate);
+        let padding = " ".repeat(MAX_DECOMPRESSED_SIZE as usize);
+
+        // Exactly at the limit is fine
+        let at_limit = format!("{}{}", &padding[2..], "{}");
+        assert_eq!(codec.decode(&deflate(at_limit.as_bytes())).unwrap(), json!({}));
+
+        let over_limit = format!("{}{}", &padding[1..], "{}");
+        let compressed = deflate(over_limit.as_bytes());
+        assert!(compressed.len() < 16 * 1024);
+        assert!(matches!(codec.decode(&compressed), Err(CodecError::TooLarge)));
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let json = codec(Encoding::Json, FrameCompression::None);
+        assert!(matches!(json.decode(b"{\"unterminated"), Err(CodecError::Json(_))));
+
+        let msgpack = codec(Encoding::Msgpack, FrameCompression::None);
+        assert!(matches!(msgpack.decode(&[0xc1]), Err(CodecError::MsgpackDecode(_))));
+
+        let deflate = codec(Encoding::Json, FrameCompression::Deflate);
+        assert!(matches!(deflate.decode(&[0xff, 0xff, 0xff]), Err(CodecError::Compression(_))));
+    }
+}
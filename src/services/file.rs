@@ -1,92 +1,703 @@
 use crate::{
     database::Database,
-    models::{File, FileResponse},
+    models::{File, FileResponse, PresignUploadRequest, PresignedUpload, PresignedUploadResponse, Upload},
+    services::{
+        file_url::FileUrlSigner,
+        storage::{ByteStream, Storage, StorageBackend, StorageKind},
+    },
 };
 use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
 use mime_guess;
-use std::path::Path;
-use tokio::fs;
+use sha2::{Digest, Sha256};
+use sqlx::PgExecutor;
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 
+/// Errors from file operations that map to a client error rather than a server error.
+#[derive(Debug, Error)]
+pub enum FileError {
+    #[error("File not found")]
+    NotFound,
+    #[error("File exceeds the maximum size of {0} bytes")]
+    TooLarge(usize),
+    #[error("Upload not found")]
+    UploadNotFound,
+    #[error("Upload is at offset {0}")]
+    OffsetMismatch(i64),
+    #[error("Upload is incomplete at offset {0}")]
+    UploadIncomplete(i64),
+    #[error("Upload is already being written to")]
+    UploadBusy,
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("Invalid file URL signature")]
+    InvalidSignature,
+    #[error("File URL has expired")]
+    UrlExpired,
+    #[error("Presigned uploads need object storage")]
+    PresignUnsupported,
+}
+
+impl FileError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            FileError::NotFound => "file_not_found",
+            FileError::TooLarge(_) => "file_too_large",
+            FileError::UploadNotFound => "upload_not_found",
+            FileError::OffsetMismatch(_) => "upload_offset_mismatch",
+            FileError::UploadIncomplete(_) => "upload_incomplete",
+            FileError::UploadBusy => "upload_busy",
+            FileError::InvalidRequest(_) => "invalid_request",
+            FileError::InvalidSignature => "invalid_signature",
+            FileError::UrlExpired => "url_expired",
+            FileError::PresignUnsupported => "presign_unsupported",
+        }
+    }
+}
+
+/// A file being written to disk as it arrives, hashed on the way. The partial file is
+/// removed unless the upload is finished with `FileService::finish_upload`.
+pub struct PendingUpload {
+    file: fs::File,
+    temp_path: PathBuf,
+    hasher: Sha256,
+    size: usize,
+    max_size: usize,
+    finished: bool,
+}
+
+impl PendingUpload {
+    /// Appends a chunk, failing with `FileError::TooLarge` as soon as the upload exceeds
+    /// the maximum size.
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        self.size += chunk.len();
+        if self.size > self.max_size {
+            return Err(FileError::TooLarge(self.max_size).into());
+        }
+
+        self.hasher.update(chunk);
+        self.file.write_all(chunk).await?;
+        Ok(())
+    }
+}
+
+impl Drop for PendingUpload {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = std::fs::remove_file(&self.temp_path) {
+                tracing::warn!("Failed to remove partial upload {}: {}", self.temp_path.display(), e);
+            }
+        }
+    }
+}
+
+/// Marks a resumable upload as being written to on this node until dropped.
+struct UploadLock {
+    upload_id: Uuid,
+    active: Arc<Mutex<HashSet<Uuid>>>,
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        self.active.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.upload_id);
+    }
+}
+
+/// How many files `migrate_storage` moved, and how many it couldn't.
+#[derive(Debug, Default)]
+pub struct MigrationSummary {
+    pub moved: usize,
+    pub failed: usize,
+}
+
+/// Files read per batch while migrating between storage backends.
+const MIGRATION_BATCH_SIZE: i64 = 100;
+/// How long a redirect to a presigned download URL can be followed.
+const PRESIGNED_DOWNLOAD_SECS: u64 = 15 * 60;
+
+/// Stores file contents in the configured storage backends. Uploads are received into
+/// temporary files in `upload_dir` before they're handed to the backend.
 #[derive(Clone)]
 pub struct FileService {
     db: Database,
+    storage: Storage,
     upload_dir: String,
+    max_file_size: usize,
+    upload_expiration: i64,
+    active_uploads: Arc<Mutex<HashSet<Uuid>>>,
+    urls: FileUrlSigner,
+    presigned_downloads: bool,
 }
 
 impl FileService {
-    pub fn new(db: Database, upload_dir: String) -> Self {
-        Self { db, upload_dir }
+    pub fn new(
+        db: Database,
+        storage: Storage,
+        upload_dir: String,
+        max_file_size: usize,
+        upload_expiration: i64,
+        urls: FileUrlSigner,
+        presigned_downloads: bool,
+    ) -> Self {
+        Self {
+            db,
+            storage,
+            upload_dir,
+            max_file_size,
+            upload_expiration,
+            active_uploads: Arc::new(Mutex::new(HashSet::new())),
+            urls,
+            presigned_downloads,
+        }
     }
 
-    pub async fn save_file(
+    pub fn max_file_size(&self) -> usize {
+        self.max_file_size
+    }
+
+    /// Starts writing an upload to a temporary file in the upload directory.
+    pub async fn begin_upload(&self) -> Result<PendingUpload> {
+        // Create upload directory if it doesn't exist
+        fs::create_dir_all(&self.upload_dir).await?;
+
+        let temp_path = Path::new(&self.upload_dir).join(format!(".{}.part", Uuid::new_v4()));
+        let file = fs::File::create(&temp_path).await?;
+
+        Ok(PendingUpload {
+            file,
+            temp_path,
+            hasher: Sha256::new(),
+            size: 0,
+            max_size: self.max_file_size,
+            finished: false,
+        })
+    }
+
+    /// Moves a completely received upload into place and records it.
+    pub async fn finish_upload(
         &self,
+        mut upload: PendingUpload,
         uploader_id: Uuid,
         filename: &str,
-        content: &[u8],
     ) -> Result<FileResponse> {
-        // Create upload directory if it doesn't exist
-        fs::create_dir_all(&self.upload_dir).await?;
+        upload.file.flush().await?;
+        upload.file.sync_all().await?;
 
-        // Generate unique filename
-        let file_id = Uuid::new_v4();
-        let extension = Path::new(filename)
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("");
-        
-        let stored_filename = if extension.is_empty() {
-            file_id.to_string()
-        } else {
-            format!("{}.{}", file_id, extension)
-        };
+        let content_hash = format!("{:x}", upload.hasher.finalize_reset());
+        // The partial file is moved away even if recording it fails
+        upload.finished = true;
+        self.store_file(&upload.temp_path, uploader_id, filename, upload.size as i64, &content_hash)
+            .await
+    }
 
-        let file_path = format!("{}/{}", self.upload_dir, stored_filename);
+    // Hands a complete temporary file to the storage backend and inserts its files row
+    async fn store_file(
+        &self,
+        temp_path: &Path,
+        uploader_id: Uuid,
+        filename: &str,
+        file_size: i64,
+        content_hash: &str,
+    ) -> Result<FileResponse> {
+        let (file_id, storage_key) = storage_key(filename);
 
         // Determine file type
         let file_type = mime_guess::from_path(filename)
             .first_or_octet_stream()
             .to_string();
 
-        // Save file to disk
-        fs::write(&file_path, content).await?;
+        let kind = self.storage.default_kind();
+        let stored = match self.storage.get(kind) {
+            Ok(backend) => backend.put_file(&storage_key, temp_path, &file_type).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = stored {
+            if let Err(e) = fs::remove_file(temp_path).await {
+                if e.kind() != ErrorKind::NotFound {
+                    tracing::warn!("Failed to remove upload {}: {}", temp_path.display(), e);
+                }
+            }
+            return Err(e);
+        }
 
-        // Save file metadata to database
-        sqlx::query(
-            "INSERT INTO files (id, filename, original_filename, file_type, file_size, file_path, uploader_id) 
-             VALUES ($1, $2, $3, $4, $5, $6, $7)"
+        let file = File {
+            id: file_id,
+            filename: storage_key.clone(),
+            original_filename: filename.to_string(),
+            file_type,
+            file_size,
+            uploader_id,
+            created_at: chrono::Utc::now(),
+            content_hash: Some(content_hash.to_string()),
+            storage_backend: kind.as_str().to_string(),
+            storage_key,
+        };
+        self.insert_file(file).await
+    }
+
+    // Records a file whose contents are already in storage, deleting them if that fails
+    async fn insert_file(&self, file: File) -> Result<FileResponse> {
+        match insert_file_row(self.db.pool(), &file).await {
+            Ok(file) => {
+                let mut response = file.to_response("");
+                response.url = self.urls.sign(file.id);
+                Ok(response)
+            }
+            Err(e) => {
+                self.delete_contents(&file).await;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Reserves a place in object storage for the client to upload a file to directly,
+    /// so the bytes don't pass through this server.
+    pub async fn presign_upload(&self, uploader_id: Uuid, request: PresignUploadRequest) -> Result<PresignedUploadResponse> {
+        if request.file_size < 0 {
+            return Err(FileError::InvalidRequest("File size must not be negative".to_string()).into());
+        }
+        if request.file_size as u64 > self.max_file_size as u64 {
+            return Err(FileError::TooLarge(self.max_file_size).into());
+        }
+
+        let (upload_id, storage_key) = storage_key(&request.filename);
+        let file_type = request
+            .content_type
+            .filter(|content_type| !content_type.trim().is_empty())
+            .unwrap_or_else(|| mime_guess::from_path(&request.filename).first_or_octet_stream().to_string());
+
+        let kind = self.storage.default_kind();
+        let url = self
+            .storage
+            .get(kind)?
+            .presign_put(
+                &staging_key(&storage_key),
+                Duration::from_secs(self.upload_expiration.max(1) as u64),
+                &file_type,
+                request.file_size as u64,
+            )
+            .ok_or(FileError::PresignUnsupported)?;
+
+        let upload = sqlx::query_as::<_, PresignedUpload>(
+            "INSERT INTO presigned_uploads (id, uploader_id, filename, file_type, file_size, storage_backend, storage_key, expires_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, NOW() + make_interval(secs => $8))
+             RETURNING *"
         )
-        .bind(file_id)
-        .bind(&stored_filename)
-        .bind(filename)
+        .bind(upload_id)
+        .bind(uploader_id)
+        .bind(&request.filename)
         .bind(&file_type)
-        .bind(content.len() as i64)
-        .bind(&file_path)
+        .bind(request.file_size)
+        .bind(kind.as_str())
+        .bind(&storage_key)
+        .bind(self.upload_expiration as f64)
+        .fetch_one(self.db.pool())
+        .await?;
+
+        Ok(PresignedUploadResponse {
+            id: upload.id,
+            method: "PUT".to_string(),
+            url,
+            headers: HashMap::from([("Content-Type".to_string(), upload.file_type)]),
+            expires_at: upload.expires_at,
+        })
+    }
+
+    /// Records a presigned upload as a file once its contents are in storage. They're
+    /// copied from where the client uploaded them, so the presigned URL, which stays valid
+    /// until it expires, can't be used to change the file afterwards.
+    pub async fn complete_presigned_upload(&self, upload_id: Uuid, uploader_id: Uuid) -> Result<FileResponse> {
+        let upload = sqlx::query_as::<_, PresignedUpload>(
+            "SELECT * FROM presigned_uploads WHERE id = $1 AND uploader_id = $2 AND expires_at > NOW()"
+        )
+        .bind(upload_id)
+        .bind(uploader_id)
+        .fetch_optional(self.db.pool())
+        .await?
+        .ok_or(FileError::UploadNotFound)?;
+
+        let kind: StorageKind = upload.storage_backend.parse().map_err(|e: String| anyhow!(e))?;
+        let backend = self.storage.get(kind)?;
+        let staging_key = staging_key(&upload.storage_key);
+        match backend.size(&staging_key).await? {
+            None => return Err(FileError::UploadIncomplete(0).into()),
+            Some(size) if size != upload.file_size as u64 => {
+                return Err(FileError::InvalidRequest(format!(
+                    "Uploaded {} bytes instead of {}",
+                    size, upload.file_size
+                ))
+                .into());
+            }
+            Some(_) => {}
+        }
+
+        // Only one request gets to record it. The upload stays claimed while its contents
+        // are copied, and is back if that fails.
+        let mut tx = self.db.pool().begin().await?;
+        let upload = sqlx::query_as::<_, PresignedUpload>("DELETE FROM presigned_uploads WHERE id = $1 RETURNING *")
+            .bind(upload_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(FileError::UploadNotFound)?;
+        backend.copy(&staging_key, &upload.storage_key, &upload.file_type).await?;
+
+        // The hash would mean reading the whole object back, so there's none
+        let file = File {
+            id: upload.id,
+            filename: upload.storage_key.clone(),
+            original_filename: upload.filename,
+            file_type: upload.file_type,
+            file_size: upload.file_size,
+            uploader_id: upload.uploader_id,
+            created_at: chrono::Utc::now(),
+            content_hash: None,
+            storage_backend: upload.storage_backend,
+            storage_key: upload.storage_key,
+        };
+        let recorded = match insert_file_row(&mut *tx, &file).await {
+            Ok(recorded) => tx.commit().await.map(|_| recorded),
+            Err(e) => Err(e),
+        };
+        let recorded = match recorded {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                self.delete_contents(&file).await;
+                return Err(e.into());
+            }
+        };
+
+        if let Err(e) = backend.delete(&staging_key).await {
+            tracing::warn!("Failed to delete presigned upload {}: {}", upload_id, e);
+        }
+
+        let mut response = recorded.to_response("");
+        response.url = self.urls.sign(recorded.id);
+        Ok(response)
+    }
+
+    /// Starts a resumable upload of `length` bytes, stored once they have all arrived.
+    pub async fn create_upload(&self, uploader_id: Uuid, filename: &str, length: i64) -> Result<Upload> {
+        if length < 0 {
+            return Err(FileError::InvalidRequest("Upload length must not be negative".to_string()).into());
+        }
+        if length as u64 > self.max_file_size as u64 {
+            return Err(FileError::TooLarge(self.max_file_size).into());
+        }
+
+        fs::create_dir_all(&self.upload_dir).await?;
+        let upload_id = Uuid::new_v4();
+        let temp_path = Path::new(&self.upload_dir).join(format!(".{}.upload", upload_id));
+        fs::File::create(&temp_path).await?;
+
+        let upload = sqlx::query_as::<_, Upload>(
+            "INSERT INTO uploads (id, uploader_id, filename, upload_length, temp_path, expires_at)
+             VALUES ($1, $2, $3, $4, $5, NOW() + make_interval(secs => $6))
+             RETURNING *"
+        )
+        .bind(upload_id)
         .bind(uploader_id)
-        .execute(self.db.pool())
+        .bind(filename)
+        .bind(length)
+        .bind(temp_path.to_string_lossy().as_ref())
+        .bind(self.upload_expiration as f64)
+        .fetch_one(self.db.pool())
+        .await;
+
+        match upload {
+            // An empty upload is complete right away. If storing it fails, the client
+            // can still complete it with an empty append.
+            Ok(upload) if length == 0 => match self.complete_upload(upload.clone()).await {
+                Ok(upload) => Ok(upload),
+                Err(e) => {
+                    tracing::warn!("Failed to store empty upload {}: {}", upload.id, e);
+                    Ok(upload)
+                }
+            },
+            Ok(upload) => Ok(upload),
+            Err(e) => {
+                if let Err(e) = fs::remove_file(&temp_path).await {
+                    tracing::warn!("Failed to remove partial upload {}: {}", temp_path.display(), e);
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Looks up one of the user's unexpired resumable uploads.
+    pub async fn get_upload(&self, upload_id: Uuid, uploader_id: Uuid) -> Result<Upload> {
+        let upload = sqlx::query_as::<_, Upload>(
+            "SELECT * FROM uploads WHERE id = $1 AND uploader_id = $2 AND expires_at > NOW()"
+        )
+        .bind(upload_id)
+        .bind(uploader_id)
+        .fetch_optional(self.db.pool())
+        .await?
+        .ok_or(FileError::UploadNotFound)?;
+
+        Ok(upload)
+    }
+
+    /// The file a resumable upload produced, once it's complete.
+    pub async fn get_upload_file(&self, upload_id: Uuid, uploader_id: Uuid) -> Result<FileResponse> {
+        let upload = self.get_upload(upload_id, uploader_id).await?;
+        let file_id = upload.file_id.ok_or(FileError::UploadIncomplete(upload.upload_offset))?;
+
+        let file = sqlx::query_as::<_, File>("SELECT * FROM files WHERE id = $1")
+            .bind(file_id)
+            .fetch_optional(self.db.pool())
+            .await?
+            .ok_or(FileError::NotFound)?;
+
+        let mut response = file.to_response("");
+        response.url = self.urls.sign(file.id);
+        Ok(response)
+    }
+
+    /// Appends the chunks of `stream` to a resumable upload, starting at `offset`, which must
+    /// be where the upload stands. Whatever arrives before the stream fails is kept, so the
+    /// client can resume from there. Stores the file once the upload is complete.
+    pub async fn append_upload<S, E>(&self, upload_id: Uuid, uploader_id: Uuid, offset: i64, mut stream: S) -> Result<Upload>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let _lock = self.lock_upload(upload_id)?;
+        let upload = self.get_upload(upload_id, uploader_id).await?;
+        if offset != upload.upload_offset {
+            return Err(FileError::OffsetMismatch(upload.upload_offset).into());
+        }
+
+        // Drop bytes past the recorded offset, left over from an interrupted write
+        let mut file = fs::OpenOptions::new().write(true).open(&upload.temp_path).await?;
+        file.set_len(offset as u64).await?;
+        file.seek(SeekFrom::Start(offset as u64)).await?;
+
+        let remaining = (upload.upload_length - offset) as usize;
+        let mut written = 0;
+        let mut failure = None;
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    failure = Some(FileError::InvalidRequest(format!("Upload interrupted: {}", e)));
+                    break;
+                }
+            };
+            if written + chunk.len() > remaining {
+                failure = Some(FileError::InvalidRequest("Upload exceeds its declared length".to_string()));
+                break;
+            }
+            file.write_all(&chunk).await?;
+            written += chunk.len();
+        }
+        file.flush().await?;
+        file.sync_all().await?;
+
+        let upload = sqlx::query_as::<_, Upload>(
+            "UPDATE uploads SET upload_offset = upload_offset + $2, expires_at = NOW() + make_interval(secs => $3)
+             WHERE id = $1 AND upload_offset = $4
+             RETURNING *"
+        )
+        .bind(upload_id)
+        .bind(written as i64)
+        .bind(self.upload_expiration as f64)
+        .bind(offset)
+        .fetch_optional(self.db.pool())
         .await?;
+        // Another node wrote to the upload meanwhile
+        let upload = match upload {
+            Some(upload) => upload,
+            None => {
+                let upload = self.get_upload(upload_id, uploader_id).await?;
+                return Err(FileError::OffsetMismatch(upload.upload_offset).into());
+            }
+        };
 
-        Ok(FileResponse {
+        if let Some(failure) = failure {
+            return Err(failure.into());
+        }
+        if upload.upload_offset == upload.upload_length {
+            return self.complete_upload(upload).await;
+        }
+        Ok(upload)
+    }
+
+    // Hashes the received bytes and stores a copy of them like a regular upload. The
+    // received bytes are only removed once the file is recorded, so a failure can be retried.
+    async fn complete_upload(&self, upload: Upload) -> Result<Upload> {
+        let mut file = fs::File::open(&upload.temp_path).await?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+        }
+        let content_hash = format!("{:x}", hasher.finalize());
+
+        let (file_id, storage_key) = storage_key(&upload.filename);
+        let file_type = mime_guess::from_path(&upload.filename)
+            .first_or_octet_stream()
+            .to_string();
+        let kind = self.storage.default_kind();
+        let contents = fs::File::open(&upload.temp_path).await?;
+        self.storage
+            .get(kind)?
+            .put(&storage_key, ReaderStream::new(contents).boxed(), upload.upload_length as u64, &file_type)
+            .await?;
+
+        let file = File {
             id: file_id,
-            filename: stored_filename,
-            original_filename: filename.to_string(),
+            filename: storage_key.clone(),
+            original_filename: upload.filename.clone(),
             file_type,
-            file_size: content.len() as i64,
-            url: format!("/api/files/{}", file_id),
+            file_size: upload.upload_length,
+            uploader_id: upload.uploader_id,
             created_at: chrono::Utc::now(),
+            content_hash: Some(content_hash),
+            storage_backend: kind.as_str().to_string(),
+            storage_key,
+        };
+        let completed = match self.record_upload_file(&upload, &file).await {
+            Ok(completed) => completed,
+            Err(e) => {
+                self.delete_contents(&file).await;
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = fs::remove_file(&upload.temp_path).await {
+            tracing::warn!("Failed to remove completed upload {}: {}", upload.temp_path, e);
+        }
+        Ok(completed)
+    }
+
+    // Inserts the files row for a completed upload and links the upload to it, together
+    async fn record_upload_file(&self, upload: &Upload, file: &File) -> Result<Upload> {
+        let mut tx = self.db.pool().begin().await?;
+        insert_file_row(&mut *tx, file).await?;
+        let upload = sqlx::query_as::<_, Upload>(
+            "UPDATE uploads SET file_id = $2 WHERE id = $1 AND file_id IS NULL RETURNING *"
+        )
+        .bind(upload.id)
+        .bind(file.id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(FileError::UploadNotFound)?;
+        tx.commit().await?;
+
+        Ok(upload)
+    }
+
+    /// Cancels a resumable upload. A file it already produced is kept.
+    pub async fn delete_upload(&self, upload_id: Uuid, uploader_id: Uuid) -> Result<()> {
+        let _lock = self.lock_upload(upload_id)?;
+        let upload = sqlx::query_as::<_, Upload>(
+            "DELETE FROM uploads WHERE id = $1 AND uploader_id = $2 RETURNING *"
+        )
+        .bind(upload_id)
+        .bind(uploader_id)
+        .fetch_optional(self.db.pool())
+        .await?
+        .ok_or(FileError::UploadNotFound)?;
+
+        self.remove_partial(&upload).await;
+        Ok(())
+    }
+
+    /// Deletes expired resumable uploads along with the bytes received for unfinished ones,
+    /// and presigned uploads that were never completed along with anything sent for them.
+    pub async fn expire_uploads(&self) -> Result<usize> {
+        let expired = sqlx::query_as::<_, Upload>("DELETE FROM uploads WHERE expires_at <= NOW() RETURNING *")
+            .fetch_all(self.db.pool())
+            .await?;
+
+        for upload in &expired {
+            self.remove_partial(upload).await;
+        }
+
+        let expired_presigned = sqlx::query_as::<_, PresignedUpload>(
+            "DELETE FROM presigned_uploads WHERE expires_at <= NOW() RETURNING *"
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        for upload in &expired_presigned {
+            let deleted = match upload.storage_backend.parse::<StorageKind>() {
+                Ok(kind) => match self.storage.get(kind) {
+                    Ok(backend) => backend.delete(&staging_key(&upload.storage_key)).await,
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(anyhow!(e)),
+            };
+            if let Err(e) = deleted {
+                tracing::warn!("Failed to delete presigned upload {}: {}", upload.id, e);
+            }
+        }
+
+        Ok(expired.len() + expired_presigned.len())
+    }
+
+    async fn remove_partial(&self, upload: &Upload) {
+        if upload.file_id.is_none() {
+            if let Err(e) = fs::remove_file(&upload.temp_path).await {
+                tracing::warn!("Failed to remove partial upload {}: {}", upload.temp_path, e);
+            }
+        }
+    }
+
+    // Only one request may write to an upload at a time
+    fn lock_upload(&self, upload_id: Uuid) -> Result<UploadLock> {
+        let mut active = self.active_uploads.lock().unwrap_or_else(|e| e.into_inner());
+        if !active.insert(upload_id) {
+            return Err(FileError::UploadBusy.into());
+        }
+        Ok(UploadLock {
+            upload_id,
+            active: self.active_uploads.clone(),
         })
     }
 
-    pub async fn get_file(&self, file_id: Uuid) -> Result<(File, Vec<u8>)> {
+    /// Looks up a file's record; its contents are read with `open_file`.
+    pub async fn get_file(&self, file_id: Uuid) -> Result<File> {
         let file = sqlx::query_as::<_, File>("SELECT * FROM files WHERE id = $1")
             .bind(file_id)
             .fetch_optional(self.db.pool())
             .await?
-            .ok_or_else(|| anyhow!("File not found"))?;
+            .ok_or(FileError::NotFound)?;
 
-        let content = fs::read(&file.file_path).await?;
+        Ok(file)
+    }
+
+    /// Reads a file's contents, or the inclusive byte range `(start, end)` of them.
+    pub async fn open_file(&self, file: &File, range: Option<(u64, u64)>) -> Result<ByteStream> {
+        self.backend_of(file)?.get(&file.storage_key, range).await
+    }
 
-        Ok((file, content))
+    /// A URL to download the file from storage directly, when that's enabled and the
+    /// file's backend supports it.
+    pub fn presigned_download(&self, file: &File, disposition: &str) -> Result<Option<String>> {
+        if !self.presigned_downloads {
+            return Ok(None);
+        }
+        let expires_in = Duration::from_secs(PRESIGNED_DOWNLOAD_SECS);
+        Ok(self
+            .backend_of(file)?
+            .presign_get(&file.storage_key, expires_in, &file.file_type, disposition))
     }
 
     pub async fn delete_file(&self, file_id: Uuid, user_id: Uuid) -> Result<()> {
@@ -105,14 +716,117 @@ impl FileService {
             .execute(self.db.pool())
             .await?;
 
-        // Delete from disk
-        if let Err(e) = fs::remove_file(&file.file_path).await {
-            tracing::warn!("Failed to delete file from disk: {}", e);
+        self.delete_contents(&file).await;
+
+        Ok(())
+    }
+
+    /// Deletes a file once no message references it anymore.
+    pub async fn release_file(&self, file_id: Uuid) -> Result<()> {
+        let file = sqlx::query_as::<_, File>(
+            "DELETE FROM files WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM messages WHERE file_id = $1)
+             RETURNING *"
+        )
+        .bind(file_id)
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        if let Some(file) = file {
+            self.delete_contents(&file).await;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the contents of every file in the `from` backend to the `to` backend and
+    /// points the files at the copies, deleting the originals if `delete_source` is set.
+    /// Files that fail are logged and left where they are, so the migration can be rerun.
+    pub async fn migrate_storage(&self, from: StorageKind, to: StorageKind, delete_source: bool) -> Result<MigrationSummary> {
+        if from == to {
+            return Err(anyhow!("Source and destination backends are the same"));
+        }
+        self.storage.get(from)?;
+        self.storage.get(to)?;
+
+        let mut summary = MigrationSummary::default();
+        let mut last_id = Uuid::nil();
+        loop {
+            let files = sqlx::query_as::<_, File>(
+                "SELECT * FROM files WHERE storage_backend = $1 AND id > $2 ORDER BY id LIMIT $3"
+            )
+            .bind(from.as_str())
+            .bind(last_id)
+            .bind(MIGRATION_BATCH_SIZE)
+            .fetch_all(self.db.pool())
+            .await?;
+
+            let Some(last) = files.last() else {
+                break;
+            };
+            last_id = last.id;
+
+            for file in &files {
+                match self.migrate_file(file, from, to, delete_source).await {
+                    Ok(()) => summary.moved += 1,
+                    Err(e) => {
+                        tracing::warn!("Failed to migrate file {}: {}", file.id, e);
+                        summary.failed += 1;
+                    }
+                }
+            }
+            tracing::info!("Migrated {} files, {} failed", summary.moved, summary.failed);
+        }
+
+        Ok(summary)
+    }
+
+    async fn migrate_file(&self, file: &File, from: StorageKind, to: StorageKind, delete_source: bool) -> Result<()> {
+        let source = self.storage.get(from)?;
+        let destination = self.storage.get(to)?;
+
+        let contents = source.get(&file.storage_key, None).await?;
+        destination
+            .put(&file.storage_key, contents, file.file_size as u64, &file.file_type)
+            .await?;
+        let copied = destination.size(&file.storage_key).await?;
+        if copied != Some(file.file_size as u64) {
+            return Err(anyhow!("Copied {:?} bytes instead of {}", copied, file.file_size));
+        }
+
+        let updated = sqlx::query("UPDATE files SET storage_backend = $2 WHERE id = $1 AND storage_backend = $3")
+            .bind(file.id)
+            .bind(to.as_str())
+            .bind(from.as_str())
+            .execute(self.db.pool())
+            .await?;
+
+        // Deleted or moved by someone else in the meantime
+        if updated.rows_affected() == 0 {
+            destination.delete(&file.storage_key).await?;
+            return Ok(());
         }
 
+        if delete_source {
+            source.delete(&file.storage_key).await?;
+        }
         Ok(())
     }
 
+    fn backend_of(&self, file: &File) -> Result<&dyn StorageBackend> {
+        let kind: StorageKind = file.storage_backend.parse().map_err(|e: String| anyhow!(e))?;
+        self.storage.get(kind)
+    }
+
+    async fn delete_contents(&self, file: &File) {
+        let deleted = match self.backend_of(file) {
+            Ok(backend) => backend.delete(&file.storage_key).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = deleted {
+            tracing::warn!("Failed to delete contents of file {}: {}", file.id, e);
+        }
+    }
+
     pub fn is_image(&self, file_type: &str) -> bool {
         file_type.starts_with("image/")
     }
@@ -125,3 +839,43 @@ impl FileService {
         file_type.starts_with("audio/")
     }
 }
+
+// Where the client uploads a presigned upload to, apart from the file's own key
+fn staging_key(storage_key: &str) -> String {
+    format!("staging/{}", storage_key)
+}
+
+// Inserts a files row, in a transaction or not
+async fn insert_file_row<'e, E: PgExecutor<'e>>(executor: E, file: &File) -> sqlx::Result<File> {
+    sqlx::query_as::<_, File>(
+        "INSERT INTO files (id, filename, original_filename, file_type, file_size, uploader_id, content_hash, storage_backend, storage_key) 
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+         RETURNING *"
+    )
+    .bind(file.id)
+    .bind(&file.filename)
+    .bind(&file.original_filename)
+    .bind(&file.file_type)
+    .bind(file.file_size)
+    .bind(file.uploader_id)
+    .bind(&file.content_hash)
+    .bind(&file.storage_backend)
+    .bind(&file.storage_key)
+    .fetch_one(executor)
+    .await
+}
+
+// A new file id, and the key to store the file under: the id with the file's extension
+fn storage_key(filename: &str) -> (Uuid, String) {
+    let file_id = Uuid::new_v4();
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+
+    if extension.is_empty() {
+        (file_id, file_id.to_string())
+    } else {
+        (file_id, format!("{}.{}", file_id, extension))
+    }
+}
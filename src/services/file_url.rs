@@ -0,0 +1,163 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::services::file::FileError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs file download URLs so they work without a bearer token, e.g. in `<img>` tags,
+/// until they expire. Expiry is rounded up to the end of the next `ttl` window, so a URL
+/// is valid for at least `ttl` seconds and stays the same for a while, letting browsers
+/// cache the file.
+#[derive(Clone)]
+pub struct FileUrlSigner {
+    secret: Arc<[u8]>,
+    ttl: i64,
+}
+
+impl FileUrlSigner {
+    pub fn new(secret: &str, ttl: i64) -> Self {
+        Self {
+            secret: secret.as_bytes().into(),
+            ttl: ttl.max(1),
+        }
+    }
+
+    /// A signing key derived from `master` (the JWT secret) for when no separate one is
+    /// configured, so a signed URL can't be mistaken for anything signed with `master` itself.
+    pub fn derive_secret(master: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(master.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(b"file-url");
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// A download URL for the file with `expires` and `signature` query parameters.
+    pub fn sign(&self, file_id: Uuid) -> String {
+        let expires = (Utc::now().timestamp() / self.ttl + 2) * self.ttl;
+        let signature = hex::encode(self.mac(file_id, expires).finalize().into_bytes());
+        format!("/api/files/{}?expires={}&signature={}", file_id, expires, signature)
+    }
+
+    /// Checks the signature of a download URL, then that it hasn't expired.
+    pub fn verify(&self, file_id: Uuid, expires: i64, signature: &str) -> Result<(), FileError> {
+        let signature = hex::decode(signature).map_err(|_| FileError::InvalidSignature)?;
+        self.mac(file_id, expires)
+            .verify_slice(&signature)
+            .map_err(|_| FileError::InvalidSignature)?;
+
+        if expires <= Utc::now().timestamp() {
+            return Err(FileError::UrlExpired);
+        }
+        Ok(())
+    }
+
+    fn mac(&self, file_id: Uuid, expires: i64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(format!("{}:{}", file_id, expires).as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(url: &str) -> (i64, String) {
+        let (_, query) = url.split_once('?').unwrap();
+        let mut expires = None;
+        let mut signature = None;
+        for pair in query.split('&') {
+            match pair.split_once('=').unwrap() {
+                ("expires", value) => expires = Some(value.parse().unwrap()),
+                ("signature", value) => signature = Some(value.to_string()),
+                _ => panic!("unexpected parameter {}", pair),
+            }
+        }
+        (expires.unwrap(), signature.unwrap())
+    }
+
+    fn signature(signer: &FileUrlSigner, file_id: Uuid, expires: i64) -> String {
+        hex::encode(signer.mac(file_id, expires).finalize().into_bytes())
+    }
+
+    #[test]
+    fn signed_url_verifies() {
+        let signer = FileUrlSigner::new("secret", 3600);
+        let file_id = Uuid::new_v4();
+        let url = signer.sign(file_id);
+        assert!(url.starts_with(&format!("/api/files/{}?", file_id)));
+
+        let (expires, signature) = query(&url);
+        assert!(signer.verify(file_id, expires, &signature).is_ok());
+    }
+
+    #[test]
+    fn expiry_rolls_over_at_window_boundaries() {
+        let ttl = 60;
+        let signer = FileUrlSigner::new("secret", ttl);
+        let before = Utc::now().timestamp();
+        let (expires, _) = query(&signer.sign(Uuid::new_v4()));
+        let after = Utc::now().timestamp();
+
+        assert_eq!(expires % ttl, 0);
+        assert!(expires >= before + ttl);
+        assert!(expires <= after + 2 * ttl);
+
+        // URLs signed within the same window are identical
+        let file_id = Uuid::new_v4();
+        let first = signer.sign(file_id);
+        let second = signer.sign(file_id);
+        if Utc::now().timestamp() / ttl == after / ttl {
+            assert_eq!(first, second);
+        }
+    }
+
+    #[test]
+    fn expired_url_is_rejected() {
+        let signer = FileUrlSigner::new("secret", 60);
+        let file_id = Uuid::new_v4();
+        let expires = Utc::now().timestamp() - 1;
+        let signature = signature(&signer, file_id, expires);
+
+        assert!(matches!(signer.verify(file_id, expires, &signature), Err(FileError::UrlExpired)));
+    }
+
+    #[test]
+    fn tampered_url_is_rejected() {
+        let signer = FileUrlSigner::new("secret", 60);
+        let file_id = Uuid::new_v4();
+        let (expires, signature) = query(&signer.sign(file_id));
+
+        let mut flipped = signature.clone().into_bytes();
+        flipped[0] = if flipped[0] == b'0' { b'1' } else { b'0' };
+        let flipped = String::from_utf8(flipped).unwrap();
+
+        for (file_id, expires, signature) in [
+            (file_id, expires, flipped.as_str()),
+            (file_id, expires, &signature[..signature.len() - 2]),
+            (file_id, expires, "not hex"),
+            (file_id, expires, ""),
+            (file_id, expires + 60, signature.as_str()),
+            (Uuid::new_v4(), expires, signature.as_str()),
+        ] {
+            assert!(matches!(
+                signer.verify(file_id, expires, signature),
+                Err(FileError::InvalidSignature)
+            ));
+        }
+
+        let other = FileUrlSigner::new("other secret", 60);
+        assert!(matches!(other.verify(file_id, expires, &signature), Err(FileError::InvalidSignature)));
+    }
+
+    #[test]
+    fn derived_secret_is_distinct_from_its_master() {
+        let derived = FileUrlSigner::derive_secret("secret");
+        assert_eq!(derived, FileUrlSigner::derive_secret("secret"));
+        assert_ne!(derived, "secret");
+        assert_ne!(derived, FileUrlSigner::derive_secret("other secret"));
+    }
+}
@@ -20,7 +20,8 @@ impl FriendService {
         let rows = sqlx::query(
             "SELECT 
                 f.id, f.status, f.created_at,
-                u.id as friend_id, u.username, u.email, u.avatar_url, u.is_online, u.last_seen
+                u.id as friend_id, u.username, u.email, u.avatar_url,
+                (u.is_online AND u.presence != 'invisible') as is_online, u.last_seen
              FROM friendships f
              JOIN users u ON (
                  CASE 
@@ -57,11 +58,14 @@ impl FriendService {
 
     pub async fn send_friend_request(&self, user_id: Uuid, request: SendFriendRequestRequest) -> Result<FriendRequest> {
         // Find the target user by email
-        let target_user = sqlx::query("SELECT id, username, email, avatar_url, is_online, last_seen FROM users WHERE email = $1")
-            .bind(&request.friend_email)
-            .fetch_optional(self.db.pool())
-            .await?
-            .ok_or_else(|| anyhow!("User not found"))?;
+        let target_user = sqlx::query(
+            "SELECT id, username, email, avatar_url, (is_online AND presence != 'invisible') as is_online, last_seen
+             FROM users WHERE email = $1"
+        )
+        .bind(&request.friend_email)
+        .fetch_optional(self.db.pool())
+        .await?
+        .ok_or_else(|| anyhow!("User not found"))?;
 
         let friend_id: Uuid = target_user.get("id");
 
@@ -94,10 +98,13 @@ impl FriendService {
         .await?;
 
         // Get sender info
-        let sender = sqlx::query("SELECT id, username, email, avatar_url, is_online, last_seen FROM users WHERE id = $1")
-            .bind(user_id)
-            .fetch_one(self.db.pool())
-            .await?;
+        let sender = sqlx::query(
+            "SELECT id, username, email, avatar_url, (is_online AND presence != 'invisible') as is_online, last_seen
+             FROM users WHERE id = $1"
+        )
+        .bind(user_id)
+        .fetch_one(self.db.pool())
+        .await?;
 
         Ok(FriendRequest {
             id: friendship_id,
@@ -175,9 +182,9 @@ impl FriendService {
             "SELECT 
                 f.id, f.created_at,
                 u1.id as from_id, u1.username as from_username, u1.email as from_email, 
-                u1.avatar_url as from_avatar, u1.is_online as from_online, u1.last_seen as from_last_seen,
+                u1.avatar_url as from_avatar, (u1.is_online AND u1.presence != 'invisible') as from_online, u1.last_seen as from_last_seen,
                 u2.id as to_id, u2.username as to_username, u2.email as to_email,
-                u2.avatar_url as to_avatar, u2.is_online as to_online, u2.last_seen as to_last_seen
+                u2.avatar_url as to_avatar, (u2.is_online AND u2.presence != 'invisible') as to_online, u2.last_seen as to_last_seen
              FROM friendships f
              JOIN users u1 ON f.user_id = u1.id
              JOIN users u2 ON f.friend_id = u2.id
@@ -1,6 +1,6 @@
 use crate::{
     database::Database,
-    models::{AddMemberRequest, CreateGroupRequest, GroupResponse, GroupOwner, GroupMemberResponse, GroupMemberUser, GroupRole},
+    models::{AddMemberRequest, CreateGroupRequest, Group, GroupMember, GroupResponse, GroupOwner, GroupMemberResponse, GroupMemberUser, GroupRole},
 };
 use anyhow::{anyhow, Result};
 use sqlx::Row;
@@ -54,16 +54,22 @@ impl GroupService {
     }
 
     pub async fn create_group(&self, user_id: Uuid, request: CreateGroupRequest) -> Result<GroupResponse> {
-        let group_id = Uuid::new_v4();
-        
         // Create group
-        sqlx::query(
-            "INSERT INTO groups (id, name, description, owner_id) VALUES ($1, $2, $3, $4)"
+        let group = sqlx::query_as::<_, Group>(
+            "INSERT INTO groups (id, name, description, owner_id) VALUES ($1, $2, $3, $4) RETURNING *"
         )
-        .bind(group_id)
+        .bind(Uuid::new_v4())
         .bind(&request.name)
         .bind(&request.description)
         .bind(user_id)
+        .fetch_one(self.db.pool())
+        .await?;
+
+        // Create the group conversation, which shares the group's id
+        sqlx::query(
+            "INSERT INTO conversations (id, kind, group_id) VALUES ($1, 'group', $1)"
+        )
+        .bind(group.id)
         .execute(self.db.pool())
         .await?;
 
@@ -71,11 +77,13 @@ impl GroupService {
         sqlx::query(
             "INSERT INTO group_members (group_id, user_id, role) VALUES ($1, $2, 'owner')"
         )
-        .bind(group_id)
+        .bind(group.id)
         .bind(user_id)
         .execute(self.db.pool())
         .await?;
 
+        self.add_conversation_participant(group.id, user_id).await?;
+
         // Get owner info
         let owner = sqlx::query("SELECT id, username, avatar_url FROM users WHERE id = $1")
             .bind(user_id)
@@ -83,31 +91,23 @@ impl GroupService {
             .await?;
 
         Ok(GroupResponse {
-            id: group_id,
-            name: request.name,
-            description: request.description,
-            avatar_url: None,
+            id: group.id,
+            name: group.name,
+            description: group.description,
+            avatar_url: group.avatar_url,
             owner: GroupOwner {
                 id: owner.get("id"),
                 username: owner.get("username"),
                 avatar_url: owner.get("avatar_url"),
             },
             member_count: 1,
-            created_at: chrono::Utc::now(),
+            created_at: group.created_at,
         })
     }
 
     pub async fn add_member(&self, user_id: Uuid, group_id: Uuid, request: AddMemberRequest) -> Result<()> {
         // Check if user is owner or admin
-        let user_role = sqlx::query(
-            "SELECT role FROM group_members WHERE group_id = $1 AND user_id = $2"
-        )
-        .bind(group_id)
-        .bind(user_id)
-        .fetch_optional(self.db.pool())
-        .await?;
-
-        let role: Option<GroupRole> = user_role.map(|row| row.get("role"));
+        let role = self.get_member(group_id, user_id).await?.map(|member| member.role);
         
         match role {
             Some(GroupRole::Owner) | Some(GroupRole::Admin) => {},
@@ -145,20 +145,14 @@ impl GroupService {
         .execute(self.db.pool())
         .await?;
 
+        self.add_conversation_participant(group_id, target_user_id).await?;
+
         Ok(())
     }
 
     pub async fn remove_member(&self, user_id: Uuid, group_id: Uuid, target_user_id: Uuid) -> Result<()> {
         // Check if user is owner or admin
-        let user_role = sqlx::query(
-            "SELECT role FROM group_members WHERE group_id = $1 AND user_id = $2"
-        )
-        .bind(group_id)
-        .bind(user_id)
-        .fetch_optional(self.db.pool())
-        .await?;
-
-        let role: Option<GroupRole> = user_role.map(|row| row.get("role"));
+        let role = self.get_member(group_id, user_id).await?.map(|member| member.role);
         
         match role {
             Some(GroupRole::Owner) | Some(GroupRole::Admin) => {},
@@ -166,17 +160,8 @@ impl GroupService {
         }
 
         // Cannot remove owner
-        let target_role = sqlx::query(
-            "SELECT role FROM group_members WHERE group_id = $1 AND user_id = $2"
-        )
-        .bind(group_id)
-        .bind(target_user_id)
-        .fetch_optional(self.db.pool())
-        .await?;
-
-        if let Some(target_role_row) = target_role {
-            let target_role: GroupRole = target_role_row.get("role");
-            if matches!(target_role, GroupRole::Owner) {
+        if let Some(target_member) = self.get_member(group_id, target_user_id).await? {
+            if matches!(target_member.role, GroupRole::Owner) {
                 return Err(anyhow!("Cannot remove group owner"));
             }
         }
@@ -194,6 +179,14 @@ impl GroupService {
             return Err(anyhow!("Member not found"));
         }
 
+        sqlx::query(
+            "DELETE FROM conversation_participants WHERE conversation_id = $1 AND user_id = $2"
+        )
+        .bind(group_id)
+        .bind(target_user_id)
+        .execute(self.db.pool())
+        .await?;
+
         Ok(())
     }
 
@@ -214,7 +207,8 @@ impl GroupService {
         let rows = sqlx::query(
             "SELECT 
                 gm.id, gm.role, gm.joined_at,
-                u.id as user_id, u.username, u.email, u.avatar_url, u.is_online
+                u.id as user_id, u.username, u.email, u.avatar_url,
+                (u.is_online AND u.presence != 'invisible') as is_online
              FROM group_members gm
              JOIN users u ON gm.user_id = u.id
              WHERE gm.group_id = $1
@@ -243,4 +237,29 @@ impl GroupService {
 
         Ok(members)
     }
+
+    async fn get_member(&self, group_id: Uuid, user_id: Uuid) -> Result<Option<GroupMember>> {
+        let member = sqlx::query_as::<_, GroupMember>(
+            "SELECT * FROM group_members WHERE group_id = $1 AND user_id = $2"
+        )
+        .bind(group_id)
+        .bind(user_id)
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(member)
+    }
+
+    async fn add_conversation_participant(&self, group_id: Uuid, user_id: Uuid) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO conversation_participants (conversation_id, user_id) VALUES ($1, $2)
+             ON CONFLICT (conversation_id, user_id) DO NOTHING"
+        )
+        .bind(group_id)
+        .bind(user_id)
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(())
+    }
 }
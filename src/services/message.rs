@@ -1,154 +1,1303 @@
 use crate::{
     database::Database,
-    models::{MessageResponse, MessageSender, MessageFile, SendMessageRequest},
+    models::{
+        ChatPeer, ChatResponse, Conversation, ConversationKind, ConversationParticipant, Friendship,
+        EditMessageRequest, GroupMember, GroupRole, Message, MessageDeleted, MessageEdit, MessageReceipt,
+        MessageReactionChanged, MessageResponse, MessageSearchQuery, MessageSearchResult, MessageSender, MessageFile, MessageStatus,
+        ReactionRequest, ReactionSummary, ReplyPreview, SendMessageRequest, SyncResponse, ThreadResponse, ThreadSummary,
+        ThreadUpdated, UpdateChatSettingsRequest,
+    },
+    services::file_url::FileUrlSigner,
 };
 use anyhow::Result;
-use sqlx::Row;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{postgres::PgRow, Row};
+use std::collections::HashMap;
+use thiserror::Error;
 use uuid::Uuid;
 
+/// Errors from message operations that map to a client error rather than a server error.
+#[derive(Debug, Error)]
+pub enum MessageError {
+    #[error("Chat not found")]
+    NotFound,
+    #[error("Message not found")]
+    MessageNotFound,
+    #[error("You are not a member of this chat")]
+    NotMember,
+    #[error("You cannot send messages to this user")]
+    Blocked,
+    #[error("Only the sender can modify this message")]
+    NotSender,
+    #[error("Message can no longer be retracted")]
+    RetractWindowExpired,
+    #[error("{0}")]
+    InvalidRequest(String),
+}
+
+impl MessageError {
+    /// Stable identifier for clients to branch on instead of the message text.
+    pub fn code(&self) -> &'static str {
+        match self {
+            MessageError::NotFound => "chat_not_found",
+            MessageError::MessageNotFound => "message_not_found",
+            MessageError::NotMember => "not_member",
+            MessageError::Blocked => "blocked",
+            MessageError::NotSender => "not_sender",
+            MessageError::RetractWindowExpired => "retract_window_expired",
+            MessageError::InvalidRequest(_) => "invalid_request",
+        }
+    }
+}
+
+// Columns read by `message_from_row`
+const MESSAGE_SELECT: &str = "SELECT 
+        m.id, m.chat_id, m.content, m.message_type, m.reply_to, m.thread_root_id,
+        m.edited_at, m.deleted_at, m.created_at, m.updated_at, m.change_xid::text AS change_xid, m.change_seq,
+        u.id as sender_id, u.username as sender_username, u.avatar_url as sender_avatar,
+        f.id as file_id, f.filename, f.file_type, f.file_size,
+        rm.content as reply_content, rm.message_type as reply_message_type, rm.deleted_at as reply_deleted_at,
+        ru.id as reply_sender_id, ru.username as reply_sender_username, ru.avatar_url as reply_sender_avatar
+     FROM messages m
+     JOIN users u ON m.sender_id = u.id
+     LEFT JOIN files f ON m.file_id = f.id
+     LEFT JOIN messages rm ON m.reply_to = rm.id
+     LEFT JOIN users ru ON rm.sender_id = ru.id";
+
+// Longest quoted excerpt embedded in a reply
+const REPLY_PREVIEW_LENGTH: usize = 100;
+
+/// Which slice of a chat's history to load, relative to an anchor message.
+pub enum MessagePage {
+    Latest,
+    Before(Uuid),
+    After(Uuid),
+    Around(Uuid),
+}
+
+struct ParticipantCursor {
+    user_id: Uuid,
+    read_at: Option<DateTime<Utc>>,
+    delivered_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Clone)]
 pub struct MessageService {
     db: Database,
+    retract_window: i64,
+    file_urls: FileUrlSigner,
 }
 
 impl MessageService {
-    pub fn new(db: Database) -> Self {
-        Self { db }
+    pub fn new(db: Database, retract_window: i64, file_urls: FileUrlSigner) -> Self {
+        Self { db, retract_window, file_urls }
     }
 
     pub async fn send_message(&self, sender_id: Uuid, request: SendMessageRequest) -> Result<MessageResponse> {
-        let message_id = Uuid::new_v4();
-        
+        // The first message to another user opens the direct conversation with them
+        let conversation = match self.find_conversation(sender_id, request.chat_id).await? {
+            Some(conversation) => conversation,
+            None => {
+                if self.find_blocked_friendship(sender_id, request.chat_id).await?.is_some() {
+                    return Err(MessageError::Blocked.into());
+                }
+                self.get_or_create_direct_conversation(sender_id, request.chat_id).await?
+            }
+        };
+
+        self.ensure_participant(sender_id, &conversation).await?;
+
+        // Sharing a file grants the chat access to it, so it has to be the sender's to share
+        if let Some(file_id) = request.file_id {
+            if !self.can_access_file(sender_id, file_id).await? {
+                return Err(MessageError::InvalidRequest("File not found".to_string()).into());
+            }
+        }
+
+        if matches!(conversation.kind, ConversationKind::Direct) {
+            for participant_id in self.get_chat_participants(conversation.id).await? {
+                if participant_id != sender_id
+                    && self.find_blocked_friendship(sender_id, participant_id).await?.is_some()
+                {
+                    return Err(MessageError::Blocked.into());
+                }
+            }
+        }
+
+        // Replies must stay within the same chat; nested replies join the top-level thread
+        let replied = match request.reply_to {
+            Some(reply_to) => {
+                let replied = sqlx::query_as::<_, Message>(
+                    "SELECT * FROM messages WHERE id = $1 AND chat_id = $2"
+                )
+                .bind(reply_to)
+                .bind(conversation.id)
+                .fetch_optional(self.db.pool())
+                .await?
+                .ok_or_else(|| MessageError::InvalidRequest("Replied message not found".to_string()))?;
+                Some(replied)
+            }
+            None => None,
+        };
+        let thread_root_id = replied
+            .as_ref()
+            .map(|replied| replied.thread_root_id.unwrap_or(replied.id));
+
         // Insert message
-        sqlx::query(
-            "INSERT INTO messages (id, sender_id, chat_id, content, message_type, file_id, reply_to) 
-             VALUES ($1, $2, $3, $4, $5, $6, $7)"
+        let message = sqlx::query_as::<_, Message>(
+            "INSERT INTO messages (id, sender_id, chat_id, content, message_type, file_id, reply_to, thread_root_id) 
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+             RETURNING *"
         )
-        .bind(message_id)
+        .bind(Uuid::new_v4())
         .bind(sender_id)
-        .bind(request.chat_id)
+        .bind(conversation.id)
         .bind(&request.content)
         .bind(&request.message_type)
         .bind(request.file_id)
         .bind(request.reply_to)
+        .bind(thread_root_id)
+        .fetch_one(self.db.pool())
+        .await?;
+
+        // The thread starter and everyone who replies follow the thread
+        if let Some(root_id) = message.thread_root_id {
+            sqlx::query(
+                "INSERT INTO thread_subscriptions (thread_root_id, user_id)
+                 SELECT id, sender_id FROM messages WHERE id = $1
+                 UNION SELECT $1, $2
+                 ON CONFLICT (thread_root_id, user_id) DO NOTHING"
+            )
+            .bind(root_id)
+            .bind(sender_id)
+            .execute(self.db.pool())
+            .await?;
+        }
+
+        // Sending a message implies the sender has read the chat up to it
+        sqlx::query(
+            "UPDATE conversation_participants SET last_read_message_id = $1, last_delivered_message_id = $1
+             WHERE conversation_id = $2 AND user_id = $3"
+        )
+        .bind(message.id)
+        .bind(conversation.id)
+        .bind(sender_id)
         .execute(self.db.pool())
         .await?;
 
         // Fetch the created message with sender info
-        self.get_message_by_id(message_id).await
+        self.get_message_by_id(sender_id, message.id).await
+    }
+
+    pub async fn edit_message(&self, user_id: Uuid, message_id: Uuid, request: EditMessageRequest) -> Result<MessageResponse> {
+        let message = self.find_message(message_id).await?;
+        self.authorize_chat(user_id, message.chat_id).await?;
+
+        if message.sender_id != user_id {
+            return Err(MessageError::NotSender.into());
+        }
+
+        if message.deleted_at.is_some() {
+            return Err(MessageError::InvalidRequest("Message has been deleted".to_string()).into());
+        }
+
+        if request.content.trim().is_empty() {
+            return Err(MessageError::InvalidRequest("Message content cannot be empty".to_string()).into());
+        }
+
+        let mut tx = self.db.pool().begin().await?;
+
+        // Keep the previous version for moderators
+        sqlx::query(
+            "INSERT INTO message_edits (message_id, editor_id, previous_content) VALUES ($1, $2, $3)"
+        )
+        .bind(message.id)
+        .bind(user_id)
+        .bind(&message.content)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("UPDATE messages SET content = $1, edited_at = NOW() WHERE id = $2")
+            .bind(&request.content)
+            .bind(message.id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        self.get_message_by_id(user_id, message.id).await
+    }
+
+    /// Deletes a message for the requesting user only, or retracts it for every participant.
+    /// Senders can retract within the configured window; group owners and admins can retract
+    /// any message. Returns the event to publish and the id of a file the message released.
+    pub async fn delete_message(
+        &self,
+        user_id: Uuid,
+        message_id: Uuid,
+        for_everyone: bool,
+    ) -> Result<(MessageDeleted, Option<Uuid>)> {
+        let message = self.find_message(message_id).await?;
+        let conversation = self.authorize_chat(user_id, message.chat_id).await?;
+
+        let deleted = MessageDeleted {
+            chat_id: conversation.id,
+            message_id: message.id,
+            for_everyone,
+        };
+
+        if !for_everyone {
+            sqlx::query(
+                "INSERT INTO hidden_messages (message_id, user_id) VALUES ($1, $2)
+                 ON CONFLICT (message_id, user_id) DO NOTHING"
+            )
+            .bind(message.id)
+            .bind(user_id)
+            .execute(self.db.pool())
+            .await?;
+
+            return Ok((deleted, None));
+        }
+
+        if message.deleted_at.is_some() {
+            return Err(MessageError::InvalidRequest("Message has already been deleted".to_string()).into());
+        }
+
+        if !self.is_moderator(user_id, &conversation).await? {
+            if message.sender_id != user_id {
+                return Err(MessageError::NotSender.into());
+            }
+            if Utc::now() - message.created_at > Duration::seconds(self.retract_window) {
+                return Err(MessageError::RetractWindowExpired.into());
+            }
+        }
+
+        let mut tx = self.db.pool().begin().await?;
+
+        // Leave a tombstone so the chat history keeps its shape. Edit history stays for moderators.
+        sqlx::query(
+            "UPDATE messages SET content = NULL, file_id = NULL, deleted_at = NOW() WHERE id = $1"
+        )
+        .bind(message.id)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM message_reactions WHERE message_id = $1")
+            .bind(message.id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok((deleted, message.file_id))
     }
 
-    pub async fn get_messages(&self, chat_id: Uuid, limit: i64, offset: i64) -> Result<Vec<MessageResponse>> {
+    /// Returns prior versions of a message, newest first. Visible to the sender and to
+    /// group owners and admins.
+    pub async fn get_message_edits(&self, user_id: Uuid, message_id: Uuid) -> Result<Vec<MessageEdit>> {
+        let message = self.find_message(message_id).await?;
+        let conversation = self.authorize_chat(user_id, message.chat_id).await?;
+
+        if message.sender_id != user_id && !self.is_moderator(user_id, &conversation).await? {
+            return Err(MessageError::NotSender.into());
+        }
+
+        let edits = sqlx::query_as::<_, MessageEdit>(
+            "SELECT * FROM message_edits WHERE message_id = $1 ORDER BY edited_at DESC"
+        )
+        .bind(message.id)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(edits)
+    }
+
+    /// Lists every chat the user participates in, pinned chats first and then by last activity.
+    pub async fn get_chats(&self, user_id: Uuid) -> Result<Vec<ChatResponse>> {
         let rows = sqlx::query(
             "SELECT 
-                m.id, m.chat_id, m.content, m.message_type, m.reply_to, m.created_at,
-                u.id as sender_id, u.username as sender_username, u.avatar_url as sender_avatar,
-                f.id as file_id, f.filename, f.file_type, f.file_size
-             FROM messages m
-             JOIN users u ON m.sender_id = u.id
-             LEFT JOIN files f ON m.file_id = f.id
-             WHERE m.chat_id = $1
-             ORDER BY m.created_at DESC
-             LIMIT $2 OFFSET $3"
+                c.id, c.kind, cp.is_muted, cp.is_pinned,
+                g.name as group_name, g.avatar_url as group_avatar,
+                peer.id as peer_id, peer.username as peer_username,
+                peer.avatar_url as peer_avatar, peer.is_online as peer_online,
+                last_message.id as last_message_id,
+                COALESCE(last_message.created_at, c.created_at) as last_activity_at,
+                (SELECT COUNT(*) FROM messages um
+                 WHERE um.chat_id = c.id AND um.sender_id <> $1 AND um.deleted_at IS NULL
+                   AND NOT EXISTS (SELECT 1 FROM hidden_messages h WHERE h.message_id = um.id AND h.user_id = $1)
+                   AND um.created_at > COALESCE(
+                       (SELECT rm.created_at FROM messages rm WHERE rm.id = cp.last_read_message_id),
+                       '-infinity'
+                   )) as unread_count
+             FROM conversation_participants cp
+             JOIN conversations c ON c.id = cp.conversation_id
+             LEFT JOIN groups g ON g.id = c.group_id
+             LEFT JOIN LATERAL (
+                 SELECT u.id, u.username, u.avatar_url, (u.is_online AND u.presence != 'invisible') as is_online
+                 FROM conversation_participants op
+                 JOIN users u ON u.id = op.user_id
+                 WHERE op.conversation_id = c.id
+                 ORDER BY op.user_id = $1
+                 LIMIT 1
+             ) peer ON c.kind = 'direct'
+             LEFT JOIN LATERAL (
+                 SELECT m.id, m.created_at FROM messages m
+                 WHERE m.chat_id = c.id
+                   AND NOT EXISTS (SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = $1)
+                 ORDER BY m.created_at DESC
+                 LIMIT 1
+             ) last_message ON TRUE
+             WHERE cp.user_id = $1
+             ORDER BY cp.is_pinned DESC, last_activity_at DESC"
         )
-        .bind(chat_id)
-        .bind(limit)
-        .bind(offset)
+        .bind(user_id)
         .fetch_all(self.db.pool())
         .await?;
 
-        let mut messages = Vec::new();
+        // Load all last messages in a single query
+        let last_message_ids: Vec<Uuid> = rows
+            .iter()
+            .filter_map(|row| row.get::<Option<Uuid>, _>("last_message_id"))
+            .collect();
+        let mut last_messages: HashMap<Uuid, MessageResponse> = self
+            .get_messages_by_ids(user_id, &last_message_ids)
+            .await?
+            .into_iter()
+            .map(|message| (message.id, message))
+            .collect();
+
+        let mut chats = Vec::new();
         for row in rows {
-            let message = MessageResponse {
+            let kind: ConversationKind = row.get("kind");
+            let peer = row.get::<Option<Uuid>, _>("peer_id").map(|peer_id| ChatPeer {
+                id: peer_id,
+                username: row.get("peer_username"),
+                avatar_url: row.get("peer_avatar"),
+                is_online: row.get("peer_online"),
+            });
+            let (name, avatar_url) = match &peer {
+                Some(peer) => (peer.username.clone(), peer.avatar_url.clone()),
+                None => (
+                    row.get::<Option<String>, _>("group_name").unwrap_or_default(),
+                    row.get("group_avatar"),
+                ),
+            };
+
+            let chat = ChatResponse {
                 id: row.get("id"),
-                sender: MessageSender {
-                    id: row.get("sender_id"),
-                    username: row.get("sender_username"),
-                    avatar_url: row.get("sender_avatar"),
-                },
-                chat_id: row.get("chat_id"),
-                content: row.get("content"),
-                message_type: row.get("message_type"),
-                file: if row.get::<Option<Uuid>, _>("file_id").is_some() {
-                    Some(MessageFile {
-                        id: row.get("file_id"),
-                        filename: row.get("filename"),
-                        file_type: row.get("file_type"),
-                        file_size: row.get("file_size"),
-                        url: format!("/api/files/{}", row.get::<Uuid, _>("file_id")),
-                    })
-                } else {
-                    None
-                },
-                reply_to: row.get("reply_to"),
-                created_at: row.get("created_at"),
+                kind,
+                name,
+                avatar_url,
+                peer,
+                last_message: row
+                    .get::<Option<Uuid>, _>("last_message_id")
+                    .and_then(|id| last_messages.remove(&id)),
+                unread_count: row.get("unread_count"),
+                is_muted: row.get("is_muted"),
+                is_pinned: row.get("is_pinned"),
+                last_activity_at: row.get("last_activity_at"),
             };
-            messages.push(message);
+            chats.push(chat);
         }
 
+        Ok(chats)
+    }
+
+    pub async fn update_chat_settings(
+        &self,
+        user_id: Uuid,
+        chat_id: Uuid,
+        request: UpdateChatSettingsRequest,
+    ) -> Result<()> {
+        let conversation = self.authorize_chat(user_id, chat_id).await?;
+
+        sqlx::query(
+            "UPDATE conversation_participants
+             SET is_muted = COALESCE($1, is_muted), is_pinned = COALESCE($2, is_pinned)
+             WHERE conversation_id = $3 AND user_id = $4"
+        )
+        .bind(request.is_muted)
+        .bind(request.is_pinned)
+        .bind(conversation.id)
+        .bind(user_id)
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Loads a page of chat history, newest first, using keyset pagination on
+    /// `(created_at, id)` so concurrent inserts don't shift pages.
+    pub async fn get_messages(&self, user_id: Uuid, chat_id: Uuid, page: MessagePage, limit: i64) -> Result<Vec<MessageResponse>> {
+        let conversation = match self.find_conversation(user_id, chat_id).await? {
+            Some(conversation) => conversation,
+            // A direct chat with another user has no history until its first message
+            None if self.user_exists(chat_id).await? => return Ok(Vec::new()),
+            None => return Err(MessageError::NotFound.into()),
+        };
+        // Chats the user isn't in look the same as ones that don't exist
+        if let Err(e) = self.ensure_participant(user_id, &conversation).await {
+            if matches!(e.downcast_ref::<MessageError>(), Some(MessageError::NotMember)) {
+                return Err(MessageError::NotFound.into());
+            }
+            return Err(e);
+        }
+
+        let messages = match page {
+            MessagePage::Latest => self.fetch_page(user_id, conversation.id, None, false, limit).await?,
+            MessagePage::Before(anchor_id) => {
+                let anchor = self.find_anchor(conversation.id, anchor_id).await?;
+                self.fetch_page(user_id, conversation.id, Some(&anchor), false, limit).await?
+            }
+            MessagePage::After(anchor_id) => {
+                let anchor = self.find_anchor(conversation.id, anchor_id).await?;
+                self.fetch_page(user_id, conversation.id, Some(&anchor), true, limit).await?
+            }
+            MessagePage::Around(anchor_id) => {
+                // The anchor itself plus up to half the page on either side
+                let anchor = self.find_anchor(conversation.id, anchor_id).await?;
+                let mut messages = self.fetch_page(user_id, conversation.id, Some(&anchor), true, limit / 2).await?;
+                messages.push(self.get_message_by_id(user_id, anchor.id).await?);
+                messages.extend(
+                    self.fetch_page(user_id, conversation.id, Some(&anchor), false, (limit - 1) / 2).await?,
+                );
+                messages
+            }
+        };
+
         Ok(messages)
     }
 
-    pub async fn get_chat_participants(&self, chat_id: Uuid) -> Result<Vec<Uuid>> {
-        // Check if it's a group chat
-        let group_members = sqlx::query("SELECT user_id FROM group_members WHERE group_id = $1")
-            .bind(chat_id)
-            .fetch_all(self.db.pool())
-            .await?;
+    /// Messages strictly older (or newer, if `newer`) than the anchor, returned newest first.
+    async fn fetch_page(
+        &self,
+        user_id: Uuid,
+        chat_id: Uuid,
+        anchor: Option<&Message>,
+        newer: bool,
+        limit: i64,
+    ) -> Result<Vec<MessageResponse>> {
+        let (comparison, order) = if newer { (">", "ASC") } else { ("<", "DESC") };
+
+        // Without an anchor the page starts at either end. Kept as separate statements so
+        // both can use the (chat_id, created_at, id) index.
+        let rows = match anchor {
+            Some(anchor) => {
+                sqlx::query(
+                    &format!(
+                        "{} WHERE m.chat_id = $1
+                           AND NOT EXISTS (SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = $2)
+                           AND (m.created_at, m.id) {} ($3, $4)
+                         ORDER BY m.created_at {}, m.id {} LIMIT $5",
+                        MESSAGE_SELECT, comparison, order, order
+                    )
+                )
+                .bind(chat_id)
+                .bind(user_id)
+                .bind(anchor.created_at)
+                .bind(anchor.id)
+                .bind(limit)
+                .fetch_all(self.db.pool())
+                .await?
+            }
+            None => {
+                sqlx::query(
+                    &format!(
+                        "{} WHERE m.chat_id = $1
+                           AND NOT EXISTS (SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = $2)
+                         ORDER BY m.created_at {}, m.id {} LIMIT $3",
+                        MESSAGE_SELECT, order, order
+                    )
+                )
+                .bind(chat_id)
+                .bind(user_id)
+                .bind(limit)
+                .fetch_all(self.db.pool())
+                .await?
+            }
+        };
+
+        let mut messages: Vec<MessageResponse> = rows.iter().map(message_from_row).collect();
+        if newer {
+            messages.reverse();
+        }
+        self.decorate(user_id, &mut messages).await?;
+
+        Ok(messages)
+    }
 
-        if !group_members.is_empty() {
-            return Ok(group_members.into_iter().map(|row| row.get("user_id")).collect());
+    async fn find_anchor(&self, chat_id: Uuid, message_id: Uuid) -> Result<Message> {
+        let message = self.find_message(message_id).await?;
+        if message.chat_id != chat_id {
+            return Err(MessageError::MessageNotFound.into());
         }
+        Ok(message)
+    }
+
+    /// Returns messages in any of the user's chats created, edited, retracted or reacted to
+    /// after the cursor. Without a cursor only the current position is returned, so a
+    /// client can load history first and sync from there.
+    pub async fn sync_messages(&self, user_id: Uuid, since: Option<&str>, limit: i64) -> Result<SyncResponse> {
+        let Some(since) = since else {
+            // Every change from a transaction that is still running sorts after this
+            let xmin: String = sqlx::query("SELECT pg_snapshot_xmin(pg_current_snapshot())::text AS xmin")
+                .fetch_one(self.db.pool())
+                .await?
+                .get("xmin");
+            return Ok(SyncResponse {
+                messages: Vec::new(),
+                next_cursor: encode_sync_cursor(&xmin, 0),
+                has_more: false,
+            });
+        };
+
+        let (change_xid, change_seq) = decode_sync_cursor(since)
+            .ok_or_else(|| MessageError::InvalidRequest("Invalid sync cursor".to_string()))?;
 
-        // If not a group, it's a direct message - get participants from recent messages
-        let participants = sqlx::query(
-            "SELECT DISTINCT sender_id FROM messages WHERE chat_id = $1 LIMIT 2"
+        // Changes are ordered by transaction, and only read up to the oldest transaction
+        // still running, so nothing can later commit behind the returned cursor. Fetch one
+        // extra row to know whether another page follows.
+        let rows = sqlx::query(
+            &format!(
+                "{}
+                 JOIN conversation_participants p ON p.conversation_id = m.chat_id AND p.user_id = $1
+                 WHERE (m.change_xid, m.change_seq) > ($2::xid8, $3)
+                   AND m.change_xid < pg_snapshot_xmin(pg_current_snapshot())
+                   AND NOT EXISTS (SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = $1)
+                 ORDER BY m.change_xid, m.change_seq LIMIT $4",
+                MESSAGE_SELECT
+            )
         )
+        .bind(user_id)
+        .bind(change_xid.to_string())
+        .bind(change_seq)
+        .bind(limit + 1)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        let has_more = rows.len() as i64 > limit;
+        let rows = &rows[..rows.len().min(limit as usize)];
+
+        let next_cursor = match rows.last() {
+            Some(row) => encode_sync_cursor(row.get("change_xid"), row.get("change_seq")),
+            None => since.to_string(),
+        };
+
+        let mut messages: Vec<MessageResponse> = rows.iter().map(message_from_row).collect();
+        self.decorate(user_id, &mut messages).await?;
+
+        Ok(SyncResponse { messages, next_cursor, has_more })
+    }
+
+    /// Full-text search across every chat the user participates in, best matches first.
+    pub async fn search_messages(&self, user_id: Uuid, query: MessageSearchQuery) -> Result<Vec<MessageSearchResult>> {
+        if query.q.trim().is_empty() {
+            return Err(MessageError::InvalidRequest("Search query cannot be empty".to_string()).into());
+        }
+
+        let chat_id = match query.chat_id {
+            Some(chat_id) => Some(self.authorize_chat(user_id, chat_id).await?.id),
+            None => None,
+        };
+
+        // Snippets are marked up for display, so the message text in them is escaped first
+        let rows = sqlx::query(
+            "SELECT m.id,
+                    ts_rank(m.search_vector, q.query) as rank,
+                    ts_headline('simple',
+                        replace(replace(replace(replace(replace(m.content,
+                            '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '\"', '&quot;'), '''', '&#39;'),
+                        q.query,
+                        'StartSel=<mark>, StopSel=</mark>, MaxWords=30, MinWords=10, MaxFragments=2') as snippet
+             FROM messages m
+             JOIN conversation_participants p ON p.conversation_id = m.chat_id AND p.user_id = $1
+             CROSS JOIN websearch_to_tsquery('simple', $2) q(query)
+             WHERE m.search_vector @@ q.query
+               AND m.deleted_at IS NULL
+               AND NOT EXISTS (SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = $1)
+               AND ($3::uuid IS NULL OR m.chat_id = $3)
+               AND ($4::uuid IS NULL OR m.sender_id = $4)
+               AND ($5::message_type IS NULL OR m.message_type = $5)
+               AND ($6::timestamptz IS NULL OR m.created_at >= $6)
+               AND ($7::timestamptz IS NULL OR m.created_at < $7)
+               AND ($8::boolean IS NULL OR (m.file_id IS NOT NULL) = $8)
+             ORDER BY rank DESC, m.created_at DESC
+             LIMIT $9 OFFSET $10"
+        )
+        .bind(user_id)
+        .bind(&query.q)
         .bind(chat_id)
+        .bind(query.sender_id)
+        .bind(&query.message_type)
+        .bind(query.from)
+        .bind(query.to)
+        .bind(query.has_file)
+        .bind(query.limit.unwrap_or(20).clamp(1, 100))
+        .bind(query.offset.unwrap_or(0).max(0))
         .fetch_all(self.db.pool())
         .await?;
 
-        Ok(participants.into_iter().map(|row| row.get("sender_id")).collect())
+        // Load the matched messages in a single query
+        let message_ids: Vec<Uuid> = rows.iter().map(|row| row.get("id")).collect();
+        let mut messages: HashMap<Uuid, MessageResponse> = self
+            .get_messages_by_ids(user_id, &message_ids)
+            .await?
+            .into_iter()
+            .map(|message| (message.id, message))
+            .collect();
+
+        let results = rows
+            .iter()
+            .filter_map(|row| {
+                let message = messages.remove(&row.get("id"))?;
+                Some(MessageSearchResult {
+                    message,
+                    snippet: row.get("snippet"),
+                    rank: row.get("rank"),
+                })
+            })
+            .collect();
+
+        Ok(results)
     }
 
-    async fn get_message_by_id(&self, message_id: Uuid) -> Result<MessageResponse> {
+    /// Returns the root of the thread containing a message and all replies in it, oldest first.
+    pub async fn get_thread(&self, user_id: Uuid, message_id: Uuid) -> Result<ThreadResponse> {
+        let message = self.find_message(message_id).await?;
+        self.authorize_chat(user_id, message.chat_id).await?;
+
+        let root_id = message.thread_root_id.unwrap_or(message.id);
+        let root = self.get_message_by_id(user_id, root_id).await?;
+
+        let rows = sqlx::query(
+            &format!(
+                "{} WHERE m.thread_root_id = $1
+                   AND NOT EXISTS (SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = $2)
+                 ORDER BY m.created_at ASC",
+                MESSAGE_SELECT
+            )
+        )
+        .bind(root_id)
+        .bind(user_id)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        let mut replies: Vec<MessageResponse> = rows.iter().map(message_from_row).collect();
+        self.decorate(user_id, &mut replies).await?;
+
+        Ok(ThreadResponse { root, replies })
+    }
+
+    /// Follows or unfollows the thread containing a message.
+    pub async fn set_thread_subscription(&self, user_id: Uuid, message_id: Uuid, subscribed: bool) -> Result<()> {
+        let message = self.find_message(message_id).await?;
+        self.authorize_chat(user_id, message.chat_id).await?;
+
+        let root_id = message.thread_root_id.unwrap_or(message.id);
+        let query = if subscribed {
+            "INSERT INTO thread_subscriptions (thread_root_id, user_id) VALUES ($1, $2)
+             ON CONFLICT (thread_root_id, user_id) DO NOTHING"
+        } else {
+            "DELETE FROM thread_subscriptions WHERE thread_root_id = $1 AND user_id = $2"
+        };
+
+        sqlx::query(query)
+            .bind(root_id)
+            .bind(user_id)
+            .execute(self.db.pool())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Chat participants following a thread.
+    pub async fn get_thread_followers(&self, thread_root_id: Uuid) -> Result<Vec<Uuid>> {
+        let rows = sqlx::query(
+            "SELECT s.user_id FROM thread_subscriptions s
+             JOIN messages m ON m.id = s.thread_root_id
+             JOIN conversation_participants p ON p.conversation_id = m.chat_id AND p.user_id = s.user_id
+             WHERE s.thread_root_id = $1"
+        )
+        .bind(thread_root_id)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(rows.iter().map(|row| row.get("user_id")).collect())
+    }
+
+    /// Current reply statistics of a thread, for participants who don't follow it.
+    pub async fn get_thread_update(&self, thread_root_id: Uuid) -> Result<ThreadUpdated> {
         let row = sqlx::query(
-            "SELECT 
-                m.id, m.chat_id, m.content, m.message_type, m.reply_to, m.created_at,
-                u.id as sender_id, u.username as sender_username, u.avatar_url as sender_avatar,
-                f.id as file_id, f.filename, f.file_type, f.file_size
+            "SELECT r.chat_id, COUNT(m.id) as reply_count, MAX(m.created_at) as last_reply_at
+             FROM messages r
+             LEFT JOIN messages m ON m.thread_root_id = r.id AND m.deleted_at IS NULL
+             WHERE r.id = $1
+             GROUP BY r.id"
+        )
+        .bind(thread_root_id)
+        .fetch_optional(self.db.pool())
+        .await?
+        .ok_or(MessageError::MessageNotFound)?;
+
+        Ok(ThreadUpdated {
+            chat_id: row.get("chat_id"),
+            thread_root_id,
+            reply_count: row.get("reply_count"),
+            last_reply_at: row.get("last_reply_at"),
+        })
+    }
+
+    pub async fn add_reaction(&self, user_id: Uuid, message_id: Uuid, request: ReactionRequest) -> Result<MessageReactionChanged> {
+        let (message, request) = self.validate_reaction(user_id, message_id, request).await?;
+
+        let result = sqlx::query(
+            "INSERT INTO message_reactions (message_id, user_id, emoji, custom_emoji_id) VALUES ($1, $2, $3, $4)
+             ON CONFLICT DO NOTHING"
+        )
+        .bind(message.id)
+        .bind(user_id)
+        .bind(&request.emoji)
+        .bind(request.custom_emoji_id)
+        .execute(self.db.pool())
+        .await?;
+
+        if result.rows_affected() > 0 {
+            self.touch_message(message.id).await?;
+        }
+
+        Ok(MessageReactionChanged {
+            chat_id: message.chat_id,
+            message_id: message.id,
+            user_id,
+            emoji: request.emoji,
+            custom_emoji_id: request.custom_emoji_id,
+            added: true,
+        })
+    }
+
+    pub async fn remove_reaction(&self, user_id: Uuid, message_id: Uuid, request: ReactionRequest) -> Result<MessageReactionChanged> {
+        let (message, request) = self.validate_reaction(user_id, message_id, request).await?;
+
+        let result = sqlx::query(
+            "DELETE FROM message_reactions
+             WHERE message_id = $1 AND user_id = $2
+               AND emoji IS NOT DISTINCT FROM $3 AND custom_emoji_id IS NOT DISTINCT FROM $4"
+        )
+        .bind(message.id)
+        .bind(user_id)
+        .bind(&request.emoji)
+        .bind(request.custom_emoji_id)
+        .execute(self.db.pool())
+        .await?;
+
+        if result.rows_affected() > 0 {
+            self.touch_message(message.id).await?;
+        }
+
+        Ok(MessageReactionChanged {
+            chat_id: message.chat_id,
+            message_id: message.id,
+            user_id,
+            emoji: request.emoji,
+            custom_emoji_id: request.custom_emoji_id,
+            added: false,
+        })
+    }
+
+    // Reactions live in their own table; bump the message so sync picks up the change
+    async fn touch_message(&self, message_id: Uuid) -> Result<()> {
+        sqlx::query("UPDATE messages SET updated_at = NOW() WHERE id = $1")
+            .bind(message_id)
+            .execute(self.db.pool())
+            .await?;
+        Ok(())
+    }
+
+    async fn validate_reaction(
+        &self,
+        user_id: Uuid,
+        message_id: Uuid,
+        request: ReactionRequest,
+    ) -> Result<(Message, ReactionRequest)> {
+        let message = self.find_message(message_id).await?;
+        self.authorize_chat(user_id, message.chat_id).await?;
+
+        if message.deleted_at.is_some() {
+            return Err(MessageError::InvalidRequest("Message has been deleted".to_string()).into());
+        }
+
+        let emoji = request.emoji.map(|emoji| emoji.trim().to_string()).filter(|emoji| !emoji.is_empty());
+        match (&emoji, request.custom_emoji_id) {
+            (Some(emoji), None) if emoji.chars().count() <= 16 => {}
+            (None, Some(_)) => {}
+            _ => {
+                return Err(MessageError::InvalidRequest(
+                    "Provide either a single emoji or a custom emoji id".to_string(),
+                )
+                .into())
+            }
+        }
+
+        Ok((message, ReactionRequest { emoji, custom_emoji_id: request.custom_emoji_id }))
+    }
+
+    /// Moves the user's read cursor forward to the given message. Returns the receipt to
+    /// fan out, or `None` if the chat was already read past it.
+    pub async fn mark_read(&self, user_id: Uuid, message_id: Uuid) -> Result<Option<MessageReceipt>> {
+        self.advance_cursor(user_id, message_id, true).await
+    }
+
+    /// Moves the user's delivery cursor forward to the given message.
+    pub async fn mark_delivered(&self, user_id: Uuid, message_id: Uuid) -> Result<Option<MessageReceipt>> {
+        self.advance_cursor(user_id, message_id, false).await
+    }
+
+    async fn advance_cursor(&self, user_id: Uuid, message_id: Uuid, read: bool) -> Result<Option<MessageReceipt>> {
+        let message = self.find_message(message_id).await?;
+        let conversation = self.authorize_chat(user_id, message.chat_id).await?;
+
+        // Reading a message also marks it as delivered
+        let query = if read {
+            "UPDATE conversation_participants p
+             SET last_read_message_id = $1,
+                 last_delivered_message_id = CASE
+                     WHEN d.created_at IS NULL OR d.created_at < $4 THEN $1
+                     ELSE p.last_delivered_message_id
+                 END
+             FROM conversation_participants cp
+             LEFT JOIN messages r ON r.id = cp.last_read_message_id
+             LEFT JOIN messages d ON d.id = cp.last_delivered_message_id
+             WHERE p.id = cp.id AND p.conversation_id = $2 AND p.user_id = $3
+               AND (r.created_at IS NULL OR r.created_at < $4)"
+        } else {
+            "UPDATE conversation_participants p
+             SET last_delivered_message_id = $1
+             FROM conversation_participants cp
+             LEFT JOIN messages d ON d.id = cp.last_delivered_message_id
+             WHERE p.id = cp.id AND p.conversation_id = $2 AND p.user_id = $3
+               AND (d.created_at IS NULL OR d.created_at < $4)"
+        };
+
+        let result = sqlx::query(query)
+            .bind(message.id)
+            .bind(conversation.id)
+            .bind(user_id)
+            .bind(message.created_at)
+            .execute(self.db.pool())
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(MessageReceipt {
+            chat_id: conversation.id,
+            user_id,
+            message_id: message.id,
+            timestamp: Utc::now(),
+        }))
+    }
+
+    /// Fills in the fields of loaded messages that depend on other tables or on the viewer.
+    async fn decorate(&self, viewer_id: Uuid, messages: &mut [MessageResponse]) -> Result<()> {
+        self.attach_statuses(messages).await?;
+        self.attach_reactions(viewer_id, messages).await?;
+        self.attach_threads(viewer_id, messages).await?;
+        self.sign_file_urls(messages);
+        Ok(())
+    }
+
+    // Lets clients load attachments without a bearer token, e.g. in <img> tags
+    fn sign_file_urls(&self, messages: &mut [MessageResponse]) {
+        for file in messages.iter_mut().filter_map(|message| message.file.as_mut()) {
+            file.url = self.file_urls.sign(file.id);
+        }
+    }
+
+    async fn attach_threads(&self, viewer_id: Uuid, messages: &mut [MessageResponse]) -> Result<()> {
+        let message_ids: Vec<Uuid> = messages.iter().map(|message| message.id).collect();
+
+        let rows = sqlx::query(
+            "SELECT m.thread_root_id, COUNT(*) as reply_count, MAX(m.created_at) as last_reply_at,
+                    EXISTS (SELECT 1 FROM thread_subscriptions s
+                            WHERE s.thread_root_id = m.thread_root_id AND s.user_id = $2) as subscribed
              FROM messages m
-             JOIN users u ON m.sender_id = u.id
-             LEFT JOIN files f ON m.file_id = f.id
-             WHERE m.id = $1"
+             WHERE m.thread_root_id = ANY($1) AND m.deleted_at IS NULL
+             GROUP BY m.thread_root_id"
         )
-        .bind(message_id)
-        .fetch_one(self.db.pool())
+        .bind(&message_ids)
+        .bind(viewer_id)
+        .fetch_all(self.db.pool())
         .await?;
 
-        Ok(MessageResponse {
-            id: row.get("id"),
-            sender: MessageSender {
-                id: row.get("sender_id"),
-                username: row.get("sender_username"),
-                avatar_url: row.get("sender_avatar"),
-            },
-            chat_id: row.get("chat_id"),
-            content: row.get("content"),
-            message_type: row.get("message_type"),
-            file: if row.get::<Option<Uuid>, _>("file_id").is_some() {
-                Some(MessageFile {
-                    id: row.get("file_id"),
-                    filename: row.get("filename"),
-                    file_type: row.get("file_type"),
-                    file_size: row.get("file_size"),
-                    url: format!("/api/files/{}", row.get::<Uuid, _>("file_id")),
-                })
+        let mut threads: HashMap<Uuid, ThreadSummary> = rows
+            .iter()
+            .map(|row| {
+                let summary = ThreadSummary {
+                    reply_count: row.get("reply_count"),
+                    last_reply_at: row.get("last_reply_at"),
+                    subscribed: row.get("subscribed"),
+                };
+                (row.get("thread_root_id"), summary)
+            })
+            .collect();
+
+        for message in messages.iter_mut() {
+            message.thread = threads.remove(&message.id);
+        }
+
+        Ok(())
+    }
+
+    async fn attach_reactions(&self, viewer_id: Uuid, messages: &mut [MessageResponse]) -> Result<()> {
+        let message_ids: Vec<Uuid> = messages.iter().map(|message| message.id).collect();
+
+        let rows = sqlx::query(
+            "SELECT message_id, emoji, custom_emoji_id, COUNT(*) as count, BOOL_OR(user_id = $2) as reacted_by_me
+             FROM message_reactions
+             WHERE message_id = ANY($1)
+             GROUP BY message_id, emoji, custom_emoji_id
+             ORDER BY MIN(created_at)"
+        )
+        .bind(&message_ids)
+        .bind(viewer_id)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        let mut reactions: HashMap<Uuid, Vec<ReactionSummary>> = HashMap::new();
+        for row in rows {
+            reactions.entry(row.get("message_id")).or_default().push(ReactionSummary {
+                emoji: row.get("emoji"),
+                custom_emoji_id: row.get("custom_emoji_id"),
+                count: row.get("count"),
+                reacted_by_me: row.get("reacted_by_me"),
+            });
+        }
+
+        for message in messages.iter_mut() {
+            message.reactions = reactions.remove(&message.id).unwrap_or_default();
+        }
+
+        Ok(())
+    }
+
+    /// Derives each message's status from the read and delivery cursors of the other participants.
+    async fn attach_statuses(&self, messages: &mut [MessageResponse]) -> Result<()> {
+        let mut chat_ids: Vec<Uuid> = messages.iter().map(|message| message.chat_id).collect();
+        chat_ids.sort();
+        chat_ids.dedup();
+
+        let rows = sqlx::query(
+            "SELECT p.conversation_id, p.user_id, r.created_at as read_at, d.created_at as delivered_at
+             FROM conversation_participants p
+             LEFT JOIN messages r ON r.id = p.last_read_message_id
+             LEFT JOIN messages d ON d.id = p.last_delivered_message_id
+             WHERE p.conversation_id = ANY($1)"
+        )
+        .bind(&chat_ids)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        let mut cursors: HashMap<Uuid, Vec<ParticipantCursor>> = HashMap::new();
+        for row in rows {
+            cursors.entry(row.get("conversation_id")).or_default().push(ParticipantCursor {
+                user_id: row.get("user_id"),
+                read_at: row.get("read_at"),
+                delivered_at: row.get("delivered_at"),
+            });
+        }
+
+        for message in messages.iter_mut() {
+            let others: Vec<&ParticipantCursor> = cursors
+                .get(&message.chat_id)
+                .map(|participants| participants.iter().filter(|cursor| cursor.user_id != message.sender.id).collect())
+                .unwrap_or_default();
+
+            if others.is_empty() {
+                continue;
+            }
+
+            let reached = |at: Option<DateTime<Utc>>| at.is_some_and(|at| at >= message.created_at);
+            message.status = if others.iter().all(|cursor| reached(cursor.read_at)) {
+                MessageStatus::Read
+            } else if others
+                .iter()
+                .all(|cursor| reached(cursor.read_at) || reached(cursor.delivered_at))
+            {
+                MessageStatus::Delivered
             } else {
-                None
-            },
-            reply_to: row.get("reply_to"),
-            created_at: row.get("created_at"),
-        })
+                MessageStatus::Sent
+            };
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_chat_participants(&self, chat_id: Uuid) -> Result<Vec<Uuid>> {
+        let participants = sqlx::query_as::<_, ConversationParticipant>(
+            "SELECT * FROM conversation_participants WHERE conversation_id = $1"
+        )
+        .bind(chat_id)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(participants.into_iter().map(|participant| participant.user_id).collect())
+    }
+
+    /// Resolves a chat id sent by a client to a conversation. Besides conversation ids,
+    /// the id of another user is accepted and refers to the direct conversation with them.
+    pub async fn find_conversation(&self, user_id: Uuid, chat_id: Uuid) -> Result<Option<Conversation>> {
+        let conversation = sqlx::query_as::<_, Conversation>(
+            "SELECT * FROM conversations WHERE id = $1 OR direct_key = $2"
+        )
+        .bind(chat_id)
+        .bind(Conversation::direct_key(user_id, chat_id))
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(conversation)
+    }
+
+    /// Resolves a chat id and checks that the user is allowed to access it.
+    pub async fn authorize_chat(&self, user_id: Uuid, chat_id: Uuid) -> Result<Conversation> {
+        let conversation = self
+            .find_conversation(user_id, chat_id)
+            .await?
+            .ok_or(MessageError::NotFound)?;
+
+        self.ensure_participant(user_id, &conversation).await?;
+
+        Ok(conversation)
+    }
+
+    /// Whether the user uploaded the file or is a member of a chat it was shared in.
+    pub async fn can_access_file(&self, user_id: Uuid, file_id: Uuid) -> Result<bool> {
+        let allowed: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM files WHERE id = $1 AND uploader_id = $2)
+                 OR EXISTS (
+                     SELECT 1 FROM messages m
+                     JOIN conversations c ON c.id = m.chat_id
+                     WHERE m.file_id = $1 AND m.deleted_at IS NULL
+                       AND CASE c.kind
+                           WHEN 'group' THEN EXISTS (
+                               SELECT 1 FROM group_members gm WHERE gm.group_id = c.group_id AND gm.user_id = $2)
+                           ELSE EXISTS (
+                               SELECT 1 FROM conversation_participants cp WHERE cp.conversation_id = c.id AND cp.user_id = $2)
+                       END
+                 )"
+        )
+        .bind(file_id)
+        .bind(user_id)
+        .fetch_one(self.db.pool())
+        .await?;
+
+        Ok(allowed)
+    }
+
+    async fn ensure_participant(&self, user_id: Uuid, conversation: &Conversation) -> Result<()> {
+        let is_participant = match conversation.kind {
+            ConversationKind::Group => sqlx::query_as::<_, GroupMember>(
+                "SELECT * FROM group_members WHERE group_id = $1 AND user_id = $2"
+            )
+            .bind(conversation.group_id)
+            .bind(user_id)
+            .fetch_optional(self.db.pool())
+            .await?
+            .is_some(),
+            ConversationKind::Direct => sqlx::query_as::<_, ConversationParticipant>(
+                "SELECT * FROM conversation_participants WHERE conversation_id = $1 AND user_id = $2"
+            )
+            .bind(conversation.id)
+            .bind(user_id)
+            .fetch_optional(self.db.pool())
+            .await?
+            .is_some(),
+        };
+
+        if !is_participant {
+            return Err(MessageError::NotMember.into());
+        }
+
+        Ok(())
+    }
+
+    /// Whether the user is an owner or admin of the group behind a conversation.
+    async fn is_moderator(&self, user_id: Uuid, conversation: &Conversation) -> Result<bool> {
+        let Some(group_id) = conversation.group_id else {
+            return Ok(false);
+        };
+
+        let member = sqlx::query_as::<_, GroupMember>(
+            "SELECT * FROM group_members WHERE group_id = $1 AND user_id = $2"
+        )
+        .bind(group_id)
+        .bind(user_id)
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(matches!(member.map(|member| member.role), Some(GroupRole::Owner) | Some(GroupRole::Admin)))
+    }
+
+    async fn find_message(&self, message_id: Uuid) -> Result<Message> {
+        let message = sqlx::query_as::<_, Message>("SELECT * FROM messages WHERE id = $1")
+            .bind(message_id)
+            .fetch_optional(self.db.pool())
+            .await?
+            .ok_or(MessageError::MessageNotFound)?;
+
+        Ok(message)
+    }
+
+    async fn find_blocked_friendship(&self, user_id: Uuid, other_user_id: Uuid) -> Result<Option<Friendship>> {
+        let friendship = sqlx::query_as::<_, Friendship>(
+            "SELECT * FROM friendships
+             WHERE ((user_id = $1 AND friend_id = $2) OR (user_id = $2 AND friend_id = $1))
+               AND status = 'blocked'"
+        )
+        .bind(user_id)
+        .bind(other_user_id)
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(friendship)
+    }
+
+    async fn user_exists(&self, user_id: Uuid) -> Result<bool> {
+        let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users WHERE id = $1)")
+            .bind(user_id)
+            .fetch_one(self.db.pool())
+            .await?;
+
+        Ok(exists)
+    }
+
+    pub async fn get_or_create_direct_conversation(&self, user_id: Uuid, other_user_id: Uuid) -> Result<Conversation> {
+        let other_user = sqlx::query("SELECT id FROM users WHERE id = $1")
+            .bind(other_user_id)
+            .fetch_optional(self.db.pool())
+            .await?;
+
+        if other_user.is_none() {
+            return Err(MessageError::NotFound.into());
+        }
+
+        let direct_key = Conversation::direct_key(user_id, other_user_id);
+
+        sqlx::query(
+            "INSERT INTO conversations (kind, direct_key) VALUES ('direct', $1)
+             ON CONFLICT (direct_key) DO NOTHING"
+        )
+        .bind(&direct_key)
+        .execute(self.db.pool())
+        .await?;
+
+        let conversation = sqlx::query_as::<_, Conversation>(
+            "SELECT * FROM conversations WHERE direct_key = $1"
+        )
+        .bind(&direct_key)
+        .fetch_one(self.db.pool())
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO conversation_participants (conversation_id, user_id) VALUES ($1, $2), ($1, $3)
+             ON CONFLICT (conversation_id, user_id) DO NOTHING"
+        )
+        .bind(conversation.id)
+        .bind(user_id)
+        .bind(other_user_id)
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(conversation)
+    }
+
+    async fn get_message_by_id(&self, viewer_id: Uuid, message_id: Uuid) -> Result<MessageResponse> {
+        let row = sqlx::query(&format!("{} WHERE m.id = $1", MESSAGE_SELECT))
+            .bind(message_id)
+            .fetch_one(self.db.pool())
+            .await?;
+
+        let mut message = message_from_row(&row);
+        self.decorate(viewer_id, std::slice::from_mut(&mut message)).await?;
+
+        Ok(message)
+    }
+
+    async fn get_messages_by_ids(&self, viewer_id: Uuid, message_ids: &[Uuid]) -> Result<Vec<MessageResponse>> {
+        let rows = sqlx::query(&format!("{} WHERE m.id = ANY($1)", MESSAGE_SELECT))
+            .bind(message_ids)
+            .fetch_all(self.db.pool())
+            .await?;
+
+        let mut messages: Vec<MessageResponse> = rows.iter().map(message_from_row).collect();
+        self.decorate(viewer_id, &mut messages).await?;
+
+        Ok(messages)
+    }
+}
+
+fn message_from_row(row: &PgRow) -> MessageResponse {
+    MessageResponse {
+        id: row.get("id"),
+        sender: MessageSender {
+            id: row.get("sender_id"),
+            username: row.get("sender_username"),
+            avatar_url: row.get("sender_avatar"),
+        },
+        chat_id: row.get("chat_id"),
+        content: row.get("content"),
+        message_type: row.get("message_type"),
+        file: if row.get::<Option<Uuid>, _>("file_id").is_some() {
+            Some(MessageFile {
+                id: row.get("file_id"),
+                filename: row.get("filename"),
+                file_type: row.get("file_type"),
+                file_size: row.get("file_size"),
+                url: format!("/api/files/{}", row.get::<Uuid, _>("file_id")),
+            })
+        } else {
+            None
+        },
+        reply_to: row.get("reply_to"),
+        reply_preview: row.get::<Option<Uuid>, _>("reply_sender_id").map(|reply_sender_id| {
+            let deleted = row.get::<Option<DateTime<Utc>>, _>("reply_deleted_at").is_some();
+            ReplyPreview {
+                id: row.get("reply_to"),
+                sender: MessageSender {
+                    id: reply_sender_id,
+                    username: row.get("reply_sender_username"),
+                    avatar_url: row.get("reply_sender_avatar"),
+                },
+                content: row
+                    .get::<Option<String>, _>("reply_content")
+                    .filter(|_| !deleted)
+                    .map(|content| truncate_preview(&content)),
+                message_type: row.get("reply_message_type"),
+                deleted,
+            }
+        }),
+        thread_root_id: row.get("thread_root_id"),
+        thread: None,
+        status: MessageStatus::Sent,
+        edited_at: row.get("edited_at"),
+        deleted_at: row.get("deleted_at"),
+        reactions: Vec::new(),
+        created_at: row.get("created_at"),
+    }
+}
+
+fn encode_sync_cursor(change_xid: &str, change_seq: i64) -> String {
+    format!("{}.{}", change_xid, change_seq)
+}
+
+fn decode_sync_cursor(cursor: &str) -> Option<(u64, i64)> {
+    let (change_xid, change_seq) = cursor.split_once('.')?;
+    Some((change_xid.parse().ok()?, change_seq.parse().ok()?))
+}
+
+fn truncate_preview(content: &str) -> String {
+    match content.char_indices().nth(REPLY_PREVIEW_LENGTH) {
+        Some((end, _)) => format!("{}…", &content[..end]),
+        None => content.to_string(),
     }
 }
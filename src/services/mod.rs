@@ -4,7 +4,13 @@ pub mod message;
 pub mod friend;
 pub mod group;
 pub mod file;
+pub mod file_url;
 pub mod websocket;
+pub mod outbound;
+pub mod codec;
+pub mod rate_limit;
+pub mod storage;
+pub mod s3;
 
 use crate::{config::Config, database::Database};
 use anyhow::Result;
@@ -18,20 +24,52 @@ pub struct AppServices {
     pub friend: friend::FriendService,
     pub group: group::GroupService,
     pub file: file::FileService,
+    pub file_urls: file_url::FileUrlSigner,
     pub websocket: websocket::WebSocketService,
+    pub rate_limit: rate_limit::RateLimiter,
 }
 
 impl AppServices {
     pub async fn new(db: Database, config: &Config) -> Result<Self> {
         let redis_client = RedisClient::open(config.redis_url.as_str())?;
         
+        let file_urls = file_url::FileUrlSigner::new(&config.file_url_secret, config.file_url_ttl);
         let auth = auth::AuthService::new(db.clone(), config.jwt_secret.clone());
         let user = user::UserService::new(db.clone());
-        let message = message::MessageService::new(db.clone());
+        let message = message::MessageService::new(db.clone(), config.message_retract_window, file_urls.clone());
         let friend = friend::FriendService::new(db.clone());
         let group = group::GroupService::new(db.clone());
-        let file = file::FileService::new(db.clone(), config.upload_dir.clone());
-        let websocket = websocket::WebSocketService::new(redis_client);
+        let s3 = match (&config.s3_endpoint, &config.s3_bucket) {
+            (Some(endpoint), Some(bucket)) => Some(s3::S3Storage::new(
+                endpoint,
+                bucket,
+                &config.s3_region,
+                &config.s3_access_key,
+                &config.s3_secret_key,
+            )?),
+            _ => None,
+        };
+        let storage = storage::Storage::new(
+            config.storage_backend,
+            storage::LocalStorage::new(&config.upload_dir),
+            s3,
+        )?;
+        let file = file::FileService::new(
+            db.clone(),
+            storage,
+            config.upload_dir.clone(),
+            config.max_file_size,
+            config.upload_expiration,
+            file_urls.clone(),
+            config.s3_presigned_downloads,
+        );
+        let websocket = websocket::WebSocketService::new(
+            redis_client,
+            config.event_log_size,
+            config.event_log_ttl,
+            config.ws_queue_capacity,
+        );
+        let rate_limit = rate_limit::RateLimiter::new(config.ws_connection_limits, config.ws_user_limits);
 
         Ok(AppServices {
             auth,
@@ -40,7 +78,9 @@ impl AppServices {
             friend,
             group,
             file,
+            file_urls,
             websocket,
+            rate_limit,
         })
     }
 }
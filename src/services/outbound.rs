@@ -0,0 +1,268 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Close code sent to a client that fell too far behind. It should reconnect and `resume`.
+pub const SLOW_CONSUMER_CLOSE_CODE: u16 = 4000;
+
+/// How a frame counts against a connection's queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Answer to the client's own request. Never dropped, since the client asked for it;
+    /// a client that doesn't read its replies is disconnected like one that falls behind.
+    Reply,
+    /// An event the client can't do without, such as a new message.
+    Event,
+    /// A short-lived event such as typing or presence, dropped first when the queue is full.
+    Ephemeral,
+}
+
+/// Something for the connection's writer to send.
+#[derive(Debug)]
+pub enum Outbound {
+    Frame(String),
+    Ping,
+    Close(u16, String),
+}
+
+/// Counters shared by every queue on this node.
+#[derive(Default)]
+pub struct QueueMetrics {
+    pub dropped_frames: AtomicU64,
+    pub slow_consumer_disconnects: AtomicU64,
+}
+
+struct QueueState {
+    items: VecDeque<(Outbound, FrameKind)>,
+    closed: bool,
+}
+
+/// Bounded queue of frames waiting to be written to one connection. Filling up drops
+/// ephemeral frames first; when only essential events and replies are left the connection
+/// is closed with a hint to resume, since dropping them would leave gaps.
+#[derive(Clone)]
+pub struct OutboundQueue {
+    state: Arc<Mutex<QueueState>>,
+    notify: Arc<Notify>,
+    capacity: usize,
+    metrics: Arc<QueueMetrics>,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, metrics: Arc<QueueMetrics>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(QueueState {
+                items: VecDeque::new(),
+                closed: false,
+            })),
+            notify: Arc::new(Notify::new()),
+            capacity,
+            metrics,
+        }
+    }
+
+    /// Queues a frame. Returns false if the connection is closing and the frame was discarded.
+    pub fn push(&self, frame: String, kind: FrameKind) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.closed {
+            return false;
+        }
+
+        if state.items.len() >= self.capacity {
+            if kind == FrameKind::Ephemeral {
+                self.metrics.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+
+            // Make room by dropping the oldest ephemeral frame
+            match state.items.iter().position(|(_, kind)| *kind == FrameKind::Ephemeral) {
+                Some(index) => {
+                    state.items.remove(index);
+                    self.metrics.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                }
+                None => {
+                    self.metrics.slow_consumer_disconnects.fetch_add(1, Ordering::Relaxed);
+                    state.items.clear();
+                    state.items.push_back((
+                        Outbound::Close(SLOW_CONSUMER_CLOSE_CODE, "slow_consumer".to_string()),
+                        FrameKind::Reply,
+                    ));
+                    state.closed = true;
+                    drop(state);
+                    self.notify.notify_one();
+                    return false;
+                }
+            }
+        }
+
+        state.items.push_back((Outbound::Frame(frame), kind));
+        drop(state);
+        self.notify.notify_one();
+        true
+    }
+
+    /// Queues a ping, unless the queue is full and the client isn't reading anyway.
+    pub fn ping(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !state.closed && state.items.len() < self.capacity {
+            state.items.push_back((Outbound::Ping, FrameKind::Ephemeral));
+            drop(state);
+            self.notify.notify_one();
+        }
+    }
+
+    /// Sends a close frame after the frames already queued and stops accepting more.
+    pub fn close(&self, code: u16, reason: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !state.closed {
+            state.items.push_back((Outbound::Close(code, reason.to_string()), FrameKind::Reply));
+            state.closed = true;
+            drop(state);
+            self.notify.notify_one();
+        }
+    }
+
+    /// Stops accepting frames; the writer finishes once the queue is drained.
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.closed = true;
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next item to write. Returns `None` once the queue is closed and drained.
+    pub async fn next(&self) -> Option<Outbound> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(item) = Self::pop(&mut state) {
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Takes the next item if one is queued, without waiting.
+    pub fn try_next(&self) -> Option<Outbound> {
+        Self::pop(&mut self.state.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn pop(state: &mut QueueState) -> Option<Outbound> {
+        state.items.pop_front().map(|(item, _)| item)
+    }
+
+    /// Number of frames waiting to be written.
+    pub fn depth(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).items.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(capacity: usize) -> (OutboundQueue, Arc<QueueMetrics>) {
+        let metrics = Arc::new(QueueMetrics::default());
+        (OutboundQueue::new(capacity, metrics.clone()), metrics)
+    }
+
+    // Takes every queued frame, with pings as "ping" and a close as "close <code>"
+    fn drain(queue: &OutboundQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.try_next())
+            .map(|item| match item {
+                Outbound::Frame(frame) => frame,
+                Outbound::Ping => "ping".to_string(),
+                Outbound::Close(code, _) => format!("close {}", code),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drops_ephemeral_frames_before_durable_ones() {
+        let (queue, metrics) = queue(3);
+        assert!(queue.push("typing 1".to_string(), FrameKind::Ephemeral));
+        assert!(queue.push("message 1".to_string(), FrameKind::Event));
+        assert!(queue.push("typing 2".to_string(), FrameKind::Ephemeral));
+
+        // The oldest ephemeral frame makes room
+        assert!(queue.push("message 2".to_string(), FrameKind::Event));
+        // A new ephemeral frame is dropped itself rather than replacing an older one
+        assert!(queue.push("typing 3".to_string(), FrameKind::Ephemeral));
+        // Replies make room the same way events do
+        assert!(queue.push("reply".to_string(), FrameKind::Reply));
+
+        assert_eq!(drain(&queue), ["message 1", "message 2", "reply"]);
+        assert_eq!(metrics.dropped_frames.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.slow_consumer_disconnects.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn counts_dropped_frames_across_queues() {
+        let metrics = Arc::new(QueueMetrics::default());
+        for _ in 0..2 {
+            let queue = OutboundQueue::new(1, metrics.clone());
+            assert!(queue.push("message".to_string(), FrameKind::Event));
+            assert!(queue.push("typing".to_string(), FrameKind::Ephemeral));
+            assert!(queue.push("presence".to_string(), FrameKind::Ephemeral));
+        }
+        assert_eq!(metrics.dropped_frames.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn closes_slow_consumers_when_only_durable_frames_are_left() {
+        let (queue, metrics) = queue(2);
+        assert!(queue.push("message 1".to_string(), FrameKind::Event));
+        assert!(queue.push("reply".to_string(), FrameKind::Reply));
+
+        assert!(!queue.push("message 2".to_string(), FrameKind::Event));
+        assert_eq!(metrics.slow_consumer_disconnects.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.dropped_frames.load(Ordering::Relaxed), 0);
+
+        // Whatever was queued is discarded in favour of the close
+        assert_eq!(drain(&queue), [format!("close {}", SLOW_CONSUMER_CLOSE_CODE)]);
+        assert!(!queue.push("message 3".to_string(), FrameKind::Event));
+        assert_eq!(metrics.slow_consumer_disconnects.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn does_not_close_while_under_the_bound() {
+        let (queue, metrics) = queue(3);
+        for i in 0..3 {
+            assert!(queue.push(format!("message {}", i), FrameKind::Event));
+        }
+        assert_eq!(queue.depth(), 3);
+        assert_eq!(metrics.slow_consumer_disconnects.load(Ordering::Relaxed), 0);
+
+        queue.try_next();
+        assert!(queue.push("message 3".to_string(), FrameKind::Event));
+        assert_eq!(metrics.slow_consumer_disconnects.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn skips_pings_when_full() {
+        let (queue, _) = queue(2);
+        queue.ping();
+        assert!(queue.push("message".to_string(), FrameKind::Event));
+        queue.ping();
+        // A queued ping makes way for an event like any ephemeral frame
+        assert!(queue.push("reply".to_string(), FrameKind::Reply));
+        assert_eq!(drain(&queue), ["message", "reply"]);
+    }
+
+    #[tokio::test]
+    async fn close_is_sent_after_queued_frames() {
+        let (queue, _) = queue(4);
+        assert!(queue.push("message".to_string(), FrameKind::Event));
+        queue.close(1000, "bye");
+        assert!(!queue.push("late".to_string(), FrameKind::Event));
+
+        assert!(matches!(queue.next().await, Some(Outbound::Frame(frame)) if frame == "message"));
+        assert!(matches!(queue.next().await, Some(Outbound::Close(1000, _))));
+        assert!(queue.next().await.is_none());
+    }
+}
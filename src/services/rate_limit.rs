@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Allows `burst` frames at once, refilled evenly over `period`. Written as
+/// `<burst>/<seconds>` in the configuration, e.g. `10/10` for ten frames per ten seconds.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    pub const fn new(burst: u32, period_secs: u64) -> Self {
+        Self {
+            burst,
+            period: Duration::from_secs(period_secs),
+        }
+    }
+
+    fn per_sec(&self) -> f64 {
+        self.burst as f64 / self.period.as_secs_f64()
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (burst, period) = s.split_once('/').ok_or_else(|| format!("Invalid rate limit {}", s))?;
+        let burst: u32 = burst.trim().parse().map_err(|_| format!("Invalid rate limit {}", s))?;
+        let period: u64 = period.trim().parse().map_err(|_| format!("Invalid rate limit {}", s))?;
+        if burst == 0 || period == 0 {
+            return Err(format!("Invalid rate limit {}", s));
+        }
+        Ok(RateLimit::new(burst, period))
+    }
+}
+
+/// Frame types with limits of their own. Everything else shares `Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameClass {
+    SendMessage,
+    Typing,
+    Other,
+}
+
+impl FrameClass {
+    pub fn of(message_type: Option<&str>) -> Self {
+        match message_type {
+            Some("send_message") => FrameClass::SendMessage,
+            Some("typing") => FrameClass::Typing,
+            _ => FrameClass::Other,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A limit for each frame class.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimits {
+    pub send_message: RateLimit,
+    pub typing: RateLimit,
+    pub other: RateLimit,
+}
+
+impl FrameLimits {
+    fn get(&self, class: FrameClass) -> &RateLimit {
+        match class {
+            FrameClass::SendMessage => &self.send_message,
+            FrameClass::Typing => &self.typing,
+            FrameClass::Other => &self.other,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    // Tops the bucket up for the time passed, then returns how long until a token is free
+    fn refill(&mut self, limit: &RateLimit, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_sec()).min(limit.burst as f64);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / limit.per_sec()))
+        }
+    }
+
+    fn is_full(&self, limit: &RateLimit, now: Instant) -> bool {
+        now.saturating_duration_since(self.updated) >= limit.period
+    }
+}
+
+/// The buckets of one connection.
+#[derive(Debug)]
+pub struct ConnectionBuckets([TokenBucket; 3]);
+
+/// Token-bucket limits on the frames clients send, both per connection and per user
+/// across all of their connections on this node.
+#[derive(Clone)]
+pub struct RateLimiter {
+    connection: FrameLimits,
+    user: FrameLimits,
+    user_buckets: Arc<Mutex<HashMap<(Uuid, FrameClass), TokenBucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(connection: FrameLimits, user: FrameLimits) -> Self {
+        Self {
+            connection,
+            user,
+            user_buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Creates the buckets for a new connection, starting full.
+    pub fn connection_buckets(&self) -> ConnectionBuckets {
+        ConnectionBuckets([
+            TokenBucket::new(&self.connection.send_message),
+            TokenBucket::new(&self.connection.typing),
+            TokenBucket::new(&self.connection.other),
+        ])
+    }
+
+    /// Takes a token for a frame from the connection's bucket and, once authenticated, the
+    /// user's. Nothing is taken when either is empty, and the time until both have a token
+    /// again is returned instead.
+    pub fn check(
+        &self,
+        buckets: &mut ConnectionBuckets,
+        user_id: Option<Uuid>,
+        class: FrameClass,
+    ) -> Result<(), Duration> {
+        let now = Instant::now();
+        let connection_limit = self.connection.get(class);
+        let connection_bucket = &mut buckets.0[class.index()];
+        let connection_wait = connection_bucket.refill(connection_limit, now);
+
+        let Some(user_id) = user_id else {
+            return match connection_wait {
+                Some(wait) => Err(wait),
+                None => {
+                    connection_bucket.tokens -= 1.0;
+                    Ok(())
+                }
+            };
+        };
+
+        let user_limit = self.user.get(class);
+        let mut user_buckets = self.user_buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let user_bucket = user_buckets
+            .entry((user_id, class))
+            .or_insert_with(|| TokenBucket::new(user_limit));
+        let user_wait = user_bucket.refill(user_limit, now);
+
+        match connection_wait.max(user_wait) {
+            Some(wait) => Err(wait),
+            None => {
+                connection_bucket.tokens -= 1.0;
+                user_bucket.tokens -= 1.0;
+                Ok(())
+            }
+        }
+    }
+
+    /// Takes a token from the user's bucket only, for requests that don't arrive over a
+    /// connection, such as REST sends.
+    pub fn check_user(&self, user_id: Uuid, class: FrameClass) -> Result<(), Duration> {
+        let user_limit = self.user.get(class);
+        let mut user_buckets = self.user_buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let user_bucket = user_buckets
+            .entry((user_id, class))
+            .or_insert_with(|| TokenBucket::new(user_limit));
+
+        match user_bucket.refill(user_limit, Instant::now()) {
+            Some(wait) => Err(wait),
+            None => {
+                user_bucket.tokens -= 1.0;
+                Ok(())
+            }
+        }
+    }
+
+    /// Forgets user buckets that have refilled completely, which behave like new ones.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let mut user_buckets = self.user_buckets.lock().unwrap_or_else(|e| e.into_inner());
+        user_buckets.retain(|(_, class), bucket| !bucket.is_full(self.user.get(*class), now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(limit: RateLimit) -> FrameLimits {
+        FrameLimits {
+            send_message: limit,
+            typing: limit,
+            other: limit,
+        }
+    }
+
+    #[test]
+    fn parses_burst_over_seconds() {
+        let limit: RateLimit = " 10 / 20 ".parse().unwrap();
+        assert_eq!(limit.burst, 10);
+        assert_eq!(limit.period, Duration::from_secs(20));
+    }
+
+    #[test]
+    fn rejects_malformed_and_zero_limits() {
+        for input in ["", "10", "10/", "/10", "a/10", "10/b", "-1/10", "10/-1", "1.5/10", "0/10", "10/0", "1/2/3"] {
+            assert!(input.parse::<RateLimit>().is_err(), "accepted {:?}", input);
+        }
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_waits() {
+        let limit = RateLimit::new(3, 3);
+        let now = Instant::now();
+        let mut bucket = TokenBucket { tokens: 3.0, updated: now };
+
+        for _ in 0..3 {
+            assert_eq!(bucket.refill(&limit, now), None);
+            bucket.tokens -= 1.0;
+        }
+        let wait = bucket.refill(&limit, now).unwrap();
+        assert!((wait.as_secs_f64() - 1.0).abs() < 1e-6, "waits {:?}", wait);
+    }
+
+    #[test]
+    fn bucket_refills_over_time_up_to_the_burst() {
+        let limit = RateLimit::new(4, 2);
+        let start = Instant::now();
+        let mut bucket = TokenBucket { tokens: 0.0, updated: start };
+
+        // Two tokens a second
+        let wait = bucket.refill(&limit, start + Duration::from_millis(250)).unwrap();
+        assert!((bucket.tokens - 0.5).abs() < 1e-6);
+        assert!((wait.as_secs_f64() - 0.25).abs() < 1e-6, "waits {:?}", wait);
+
+        assert_eq!(bucket.refill(&limit, start + Duration::from_secs(1)), None);
+        assert!((bucket.tokens - 2.0).abs() < 1e-6);
+
+        assert_eq!(bucket.refill(&limit, start + Duration::from_secs(60)), None);
+        assert_eq!(bucket.tokens, 4.0);
+    }
+
+    #[test]
+    fn connection_and_user_limits_both_apply() {
+        let limiter = RateLimiter::new(limits(RateLimit::new(2, 60)), limits(RateLimit::new(3, 60)));
+        let user_id = Uuid::new_v4();
+        let mut first = limiter.connection_buckets();
+        let mut second = limiter.connection_buckets();
+
+        assert!(limiter.check(&mut first, Some(user_id), FrameClass::SendMessage).is_ok());
+        assert!(limiter.check(&mut first, Some(user_id), FrameClass::SendMessage).is_ok());
+        // The connection is out of tokens
+        assert!(limiter.check(&mut first, Some(user_id), FrameClass::SendMessage).is_err());
+        // Other classes have buckets of their own
+        assert!(limiter.check(&mut first, Some(user_id), FrameClass::Typing).is_ok());
+
+        assert!(limiter.check(&mut second, Some(user_id), FrameClass::SendMessage).is_ok());
+        // The user is out of tokens, across connections and for REST requests
+        assert!(limiter.check(&mut second, Some(user_id), FrameClass::SendMessage).is_err());
+        assert!(limiter.check_user(user_id, FrameClass::SendMessage).is_err());
+        assert!(limiter.check_user(Uuid::new_v4(), FrameClass::SendMessage).is_ok());
+
+        // Unauthenticated frames only count against the connection
+        let mut anonymous = limiter.connection_buckets();
+        assert!(limiter.check(&mut anonymous, None, FrameClass::SendMessage).is_ok());
+    }
+
+    #[test]
+    fn prune_forgets_idle_buckets() {
+        let limiter = RateLimiter::new(limits(RateLimit::new(5, 10)), limits(RateLimit::new(5, 10)));
+        let idle = Uuid::new_v4();
+        let active = Uuid::new_v4();
+        assert!(limiter.check_user(idle, FrameClass::Other).is_ok());
+        assert!(limiter.check_user(active, FrameClass::Other).is_ok());
+
+        {
+            let mut buckets = limiter.user_buckets.lock().unwrap();
+            let bucket = buckets.get_mut(&(idle, FrameClass::Other)).unwrap();
+            bucket.updated -= Duration::from_secs(10);
+        }
+        limiter.prune();
+
+        let buckets = limiter.user_buckets.lock().unwrap();
+        assert!(!buckets.contains_key(&(idle, FrameClass::Other)));
+        assert!(buckets.contains_key(&(active, FrameClass::Other)));
+    }
+}
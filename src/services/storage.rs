@@ -0,0 +1,314 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{stream::BoxStream, StreamExt};
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::services::{file::FileError, s3::S3Storage};
+
+/// The contents of a blob, read as it's sent.
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// Where file contents live. Recorded per file in `files.storage_backend`, so files can be
+/// moved between backends one at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Local,
+    S3,
+}
+
+impl StorageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageKind::Local => "local",
+            StorageKind::S3 => "s3",
+        }
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "local" => Ok(StorageKind::Local),
+            "s3" => Ok(StorageKind::S3),
+            _ => Err(format!("Unknown storage backend {}", s)),
+        }
+    }
+}
+
+/// A place to keep file contents, addressed by key.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Stores `length` bytes read from `body` under `key`, replacing any existing blob.
+    async fn put(&self, key: &str, body: ByteStream, length: u64, content_type: &str) -> Result<()>;
+
+    /// Stores the file at `path` under `key` and removes it from `path`.
+    async fn put_file(&self, key: &str, path: &Path, content_type: &str) -> Result<()> {
+        let file = fs::File::open(path).await?;
+        let length = file.metadata().await?.len();
+        self.put(key, ReaderStream::new(file).boxed(), length, content_type).await?;
+        fs::remove_file(path).await?;
+        Ok(())
+    }
+
+    /// Reads a blob, or the inclusive byte range `(start, end)` of it. Fails with
+    /// `FileError::NotFound` if there's no such blob.
+    async fn get(&self, key: &str, range: Option<(u64, u64)>) -> Result<ByteStream>;
+
+    /// The size of a blob, or `None` if there's no such blob.
+    async fn size(&self, key: &str) -> Result<Option<u64>>;
+
+    /// Removes a blob. Removing one that doesn't exist is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Copies a blob to another key, replacing anything there. Fails with
+    /// `FileError::NotFound` if there's no such blob.
+    async fn copy(&self, from: &str, to: &str, content_type: &str) -> Result<()> {
+        let length = self.size(from).await?.ok_or(FileError::NotFound)?;
+        let body = self.get(from, None).await?;
+        self.put(to, body, length, content_type).await
+    }
+
+    /// A URL to download the blob from storage directly, for backends that support it.
+    fn presign_get(&self, _key: &str, _expires_in: Duration, _content_type: &str, _disposition: &str) -> Option<String> {
+        None
+    }
+
+    /// A URL to `PUT` exactly `length` bytes to directly, for backends that support it.
+    fn presign_put(&self, _key: &str, _expires_in: Duration, _content_type: &str, _length: u64) -> Option<String> {
+        None
+    }
+}
+
+/// Keeps blobs as files in a directory.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    // Keys are generated file names, but never let one escape the directory
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        if key.is_empty() || key.contains('/') || key.contains('\\') || key.starts_with('.') {
+            return Err(anyhow!("Invalid storage key {}", key));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn put(&self, key: &str, mut body: ByteStream, length: u64, _content_type: &str) -> Result<()> {
+        let path = self.path(key)?;
+        fs::create_dir_all(&self.root).await?;
+
+        // Written next to the destination and moved into place once complete
+        let temp_path = self.root.join(format!(".{}.part", Uuid::new_v4()));
+        let written = async {
+            let mut file = fs::File::create(&temp_path).await?;
+            let mut size = 0;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk?;
+                file.write_all(&chunk).await?;
+                size += chunk.len() as u64;
+            }
+            file.sync_all().await?;
+            if size != length {
+                return Err(anyhow!("Expected {} bytes for {}, got {}", length, key, size));
+            }
+            fs::rename(&temp_path, &path).await?;
+            Ok(())
+        }
+        .await;
+
+        if written.is_err() {
+            if let Err(e) = fs::remove_file(&temp_path).await {
+                tracing::warn!("Failed to remove partial blob {}: {}", temp_path.display(), e);
+            }
+        }
+        written
+    }
+
+    async fn put_file(&self, key: &str, path: &Path, _content_type: &str) -> Result<()> {
+        let destination = self.path(key)?;
+        fs::create_dir_all(&self.root).await?;
+        fs::rename(path, destination).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, range: Option<(u64, u64)>) -> Result<ByteStream> {
+        let mut file = match fs::File::open(self.path(key)?).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(FileError::NotFound.into()),
+            Err(e) => return Err(e.into()),
+        };
+
+        match range {
+            Some((start, end)) => {
+                file.seek(SeekFrom::Start(start)).await?;
+                Ok(ReaderStream::new(file.take(end - start + 1)).boxed())
+            }
+            None => Ok(ReaderStream::new(file).boxed()),
+        }
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>> {
+        match fs::metadata(self.path(key)?).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.path(key)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The configured backends and the one new files go to.
+#[derive(Clone)]
+pub struct Storage {
+    default: StorageKind,
+    local: Arc<LocalStorage>,
+    s3: Option<Arc<S3Storage>>,
+}
+
+impl Storage {
+    pub fn new(default: StorageKind, local: LocalStorage, s3: Option<S3Storage>) -> Result<Self> {
+        if default == StorageKind::S3 && s3.is_none() {
+            return Err(anyhow!("STORAGE_BACKEND is s3 but S3_ENDPOINT and S3_BUCKET are not set"));
+        }
+        Ok(Self {
+            default,
+            local: Arc::new(local),
+            s3: s3.map(Arc::new),
+        })
+    }
+
+    pub fn default_kind(&self) -> StorageKind {
+        self.default
+    }
+
+    pub fn get(&self, kind: StorageKind) -> Result<&dyn StorageBackend> {
+        match kind {
+            StorageKind::Local => Ok(self.local.as_ref()),
+            StorageKind::S3 => match &self.s3 {
+                Some(s3) => Ok(s3.as_ref()),
+                None => Err(anyhow!("S3 storage is not configured")),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    // A fresh directory under the system temp dir, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("rusty-chat-storage-{}", Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn body(chunks: &[&'static [u8]]) -> ByteStream {
+        let chunks: Vec<_> = chunks.iter().map(|chunk| Ok(Bytes::from_static(chunk))).collect();
+        stream::iter(chunks).boxed()
+    }
+
+    async fn read(storage: &LocalStorage, key: &str, range: Option<(u64, u64)>) -> Vec<u8> {
+        let mut stream = storage.get(key, range).await.unwrap();
+        let mut contents = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            contents.extend_from_slice(&chunk.unwrap());
+        }
+        contents
+    }
+
+    fn is_not_found(result: Result<ByteStream>) -> bool {
+        matches!(result.err().and_then(|e| e.downcast::<FileError>().ok()), Some(FileError::NotFound))
+    }
+
+    #[test]
+    fn path_rejects_keys_outside_the_root() {
+        let storage = LocalStorage::new("/srv/files");
+        assert_eq!(storage.path("a.png").unwrap(), PathBuf::from("/srv/files/a.png"));
+        for key in ["", "../a.png", "a/b.png", "/etc/passwd", "a\\b.png", "..", ".hidden", ".a.part"] {
+            assert!(storage.path(key).is_err(), "accepted {:?}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = TempDir::new();
+        let storage = LocalStorage::new(&dir.0);
+
+        assert_eq!(storage.size("a.txt").await.unwrap(), None);
+        assert!(is_not_found(storage.get("a.txt", None).await));
+
+        storage.put("a.txt", body(&[b"hello ", b"world"]), 11, "text/plain").await.unwrap();
+        assert_eq!(storage.size("a.txt").await.unwrap(), Some(11));
+        assert_eq!(read(&storage, "a.txt", None).await, b"hello world");
+        assert_eq!(read(&storage, "a.txt", Some((6, 10))).await, b"world");
+
+        storage.copy("a.txt", "b.txt", "text/plain").await.unwrap();
+        assert_eq!(read(&storage, "b.txt", None).await, b"hello world");
+
+        storage.delete("a.txt").await.unwrap();
+        assert_eq!(storage.size("a.txt").await.unwrap(), None);
+        // Deleting again is fine
+        storage.delete("a.txt").await.unwrap();
+        assert_eq!(storage.size("b.txt").await.unwrap(), Some(11));
+    }
+
+    #[tokio::test]
+    async fn put_of_the_wrong_length_leaves_nothing_behind() {
+        let dir = TempDir::new();
+        let storage = LocalStorage::new(&dir.0);
+
+        assert!(storage.put("a.txt", body(&[b"short"]), 10, "text/plain").await.is_err());
+        assert_eq!(storage.size("a.txt").await.unwrap(), None);
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn put_file_moves_the_file() {
+        let dir = TempDir::new();
+        let storage = LocalStorage::new(dir.0.join("files"));
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let source = dir.0.join("upload.part");
+        std::fs::write(&source, b"contents").unwrap();
+
+        storage.put_file("a.bin", &source, "application/octet-stream").await.unwrap();
+        assert!(!source.exists());
+        assert_eq!(read(&storage, "a.bin", None).await, b"contents");
+    }
+}
@@ -1,10 +1,30 @@
 use crate::{
     database::Database,
-    models::{User, UserResponse},
+    models::{UpdatePresenceRequest, User, UserResponse},
 };
 use anyhow::Result;
+use chrono::Utc;
+use thiserror::Error;
 use uuid::Uuid;
 
+/// Longest custom status text, matching the column size.
+pub const MAX_STATUS_TEXT_LENGTH: usize = 128;
+
+/// Errors from user operations that map to a client error rather than a server error.
+#[derive(Debug, Error)]
+pub enum UserError {
+    #[error("{0}")]
+    InvalidRequest(String),
+}
+
+impl UserError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            UserError::InvalidRequest(_) => "invalid_request",
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct UserService {
     db: Database,
@@ -24,6 +44,15 @@ impl UserService {
         Ok(user.into())
     }
 
+    pub async fn get_user(&self, user_id: Uuid) -> Result<User> {
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(self.db.pool())
+            .await?;
+
+        Ok(user)
+    }
+
     pub async fn search_users(&self, query: &str, limit: i64) -> Result<Vec<UserResponse>> {
         let users = sqlx::query_as::<_, User>(
             "SELECT * FROM users WHERE username ILIKE $1 OR email ILIKE $1 LIMIT $2"
@@ -37,8 +66,11 @@ impl UserService {
     }
 
     pub async fn update_online_status(&self, user_id: Uuid, is_online: bool) -> Result<()> {
+        // Idle doesn't outlive the connections that reported it
         let query = if is_online {
-            "UPDATE users SET is_online = true WHERE id = $1"
+            "UPDATE users SET is_online = true,
+                presence = CASE WHEN presence = 'idle' THEN 'online' ELSE presence END
+             WHERE id = $1"
         } else {
             "UPDATE users SET is_online = false, last_seen = NOW() WHERE id = $1"
         };
@@ -51,6 +83,98 @@ impl UserService {
         Ok(())
     }
 
+    pub async fn update_presence(&self, user_id: Uuid, request: UpdatePresenceRequest) -> Result<User> {
+        if let Some(text) = &request.status_text {
+            if text.chars().count() > MAX_STATUS_TEXT_LENGTH {
+                return Err(UserError::InvalidRequest(format!(
+                    "Status text can be at most {} characters",
+                    MAX_STATUS_TEXT_LENGTH
+                ))
+                .into());
+            }
+        }
+        if request.status_expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+            return Err(UserError::InvalidRequest("Status expiry must be in the future".to_string()).into());
+        }
+
+        // New status text replaces the expiry too, so text set without one never expires
+        let user = sqlx::query_as::<_, User>(
+            "UPDATE users SET
+                presence = COALESCE($2, presence),
+                status_text = CASE WHEN $3::text IS NULL THEN status_text ELSE NULLIF(TRIM($3), '') END,
+                status_expires_at = CASE
+                    WHEN $3::text IS NULL THEN COALESCE($4, status_expires_at)
+                    WHEN TRIM($3) = '' THEN NULL
+                    ELSE $4
+                END
+             WHERE id = $1
+             RETURNING *"
+        )
+        .bind(user_id)
+        .bind(request.presence)
+        .bind(request.status_text)
+        .bind(request.status_expires_at)
+        .fetch_one(self.db.pool())
+        .await?;
+
+        Ok(user)
+    }
+
+    /// Users who see this user's presence: accepted friends and members of shared groups.
+    pub async fn get_presence_audience(&self, user_id: Uuid) -> Result<Vec<Uuid>> {
+        let user_ids = sqlx::query_scalar::<_, Uuid>(
+            "SELECT CASE WHEN f.user_id = $1 THEN f.friend_id ELSE f.user_id END
+             FROM friendships f
+             WHERE (f.user_id = $1 OR f.friend_id = $1) AND f.status = 'accepted'
+             UNION
+             SELECT other.user_id
+             FROM conversation_participants me
+             JOIN conversations c ON c.id = me.conversation_id AND c.kind = 'group'
+             JOIN conversation_participants other ON other.conversation_id = me.conversation_id
+             WHERE me.user_id = $1 AND other.user_id != $1"
+        )
+        .bind(user_id)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(user_ids)
+    }
+
+    pub async fn get_online_user_ids(&self) -> Result<Vec<Uuid>> {
+        let user_ids = sqlx::query_scalar::<_, Uuid>("SELECT id FROM users WHERE is_online")
+            .fetch_all(self.db.pool())
+            .await?;
+
+        Ok(user_ids)
+    }
+
+    /// The users among `user_ids` who are marked offline.
+    pub async fn get_offline_user_ids(&self, user_ids: &[Uuid]) -> Result<Vec<Uuid>> {
+        if user_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let user_ids = sqlx::query_scalar::<_, Uuid>("SELECT id FROM users WHERE id = ANY($1) AND NOT is_online")
+            .bind(user_ids)
+            .fetch_all(self.db.pool())
+            .await?;
+
+        Ok(user_ids)
+    }
+
+    /// Clears custom statuses past their expiry. Returns the affected users.
+    pub async fn clear_expired_status_text(&self) -> Result<Vec<Uuid>> {
+        let user_ids = sqlx::query_scalar::<_, Uuid>(
+            "UPDATE users SET status_text = NULL, status_expires_at = NULL
+             WHERE status_expires_at <= NOW()
+             RETURNING id"
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(user_ids)
+    }
+
     pub async fn update_avatar(&self, user_id: Uuid, avatar_url: String) -> Result<()> {
         sqlx::query("UPDATE users SET avatar_url = $1 WHERE id = $2")
             .bind(avatar_url)
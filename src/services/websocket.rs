@@ -1,123 +1,743 @@
-use crate::models::{MessageResponse, TypingIndicator, WebSocketMessage};
+use super::outbound::{FrameKind, OutboundQueue, QueueMetrics};
+use crate::models::{MessageReceipt, MessageResponse, ServerEvent, ServerFrame, ThreadUpdated, TypingIndicator};
 use anyhow::Result;
-use redis::{Client as RedisClient, Commands};
+use redis::{aio::MultiplexedConnection, AsyncCommands, Client as RedisClient};
+use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
+use tracing::{info, warn};
 use uuid::Uuid;
 
-pub type UserSender = broadcast::Sender<String>;
-pub type UserReceiver = broadcast::Receiver<String>;
+// Events for a user are published on `ws:user:{user_id}`, which the nodes holding their
+// connections subscribe to
+const USER_CHANNEL_PREFIX: &str = "ws:user:";
+// Numbers an event, logs it and publishes it in one step, so events reach subscribers in
+// the order of their sequence numbers even when several nodes publish for the same user.
+// KEYS: sequence, event log. ARGV: frame, log size, log TTL, channel, envelope. Both the
+// frame and the envelope are JSON objects, which get a leading `seq` field.
+const LOG_AND_PUBLISH_SCRIPT: &str = r#"
+local seq = redis.call('INCR', KEYS[1])
+local prefix = '{"seq":' .. seq .. ','
+redis.call('ZADD', KEYS[2], seq, prefix .. string.sub(ARGV[1], 2))
+redis.call('ZREMRANGEBYRANK', KEYS[2], 0, -tonumber(ARGV[2]) - 1)
+redis.call('EXPIRE', KEYS[2], ARGV[3])
+redis.call('PUBLISH', ARGV[4], prefix .. string.sub(ARGV[5], 2))
+return seq
+"#;
+// How often the subscriber stops waiting for events to apply subscription changes
+const SUBSCRIBER_POLL: Duration = Duration::from_millis(50);
+// Longest a new connection waits for its user's channel to be subscribed
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(2);
+// Set of node ids that have registered connections
+const NODES_KEY: &str = "ws:nodes";
 
+/// Seconds a WebSocket ticket can be redeemed after it is issued.
+pub const TICKET_TTL_SECS: u64 = 30;
+
+/// Seconds a node stays registered without a heartbeat before peers reap its connections.
+pub const NODE_TTL_SECS: u64 = 30;
+
+/// Identifies one WebSocket connection, so a user can be connected from several devices.
+pub type ConnectionId = Uuid;
+
+/// A newly registered connection. `first` is set when the user had no other connection.
+pub struct UserConnection {
+    pub id: ConnectionId,
+    pub first: bool,
+}
+
+/// Events logged for a user after a given sequence number. `complete` is false when some
+/// of them are no longer in the log, so the client has to resynchronize instead.
+pub struct Replay {
+    pub frames: Vec<String>,
+    pub current_seq: i64,
+    pub complete: bool,
+}
+
+/// An event published for one user, delivered by whichever nodes hold their connections.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    // Set on events numbered for replay; `payload` doesn't include it yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<i64>,
+    only: Option<ConnectionId>,
+    except: Option<ConnectionId>,
+    payload: String,
+    // Typing and presence events, which are neither logged nor kept when a client falls behind
+    #[serde(default)]
+    ephemeral: bool,
+}
+
+/// A change to the user channels this node subscribes to. `Subscribe` carries a sender
+/// that's dropped once the subscription is active.
+enum Subscription {
+    Subscribe(Uuid, oneshot::Sender<()>),
+    Unsubscribe(Uuid),
+}
+
+/// A long-polling client's connection, kept between polls until its lease runs out.
+/// The frames of the last response are kept until the next poll acknowledges its cursor,
+/// since the response may never have arrived.
+struct PollConnection {
+    user_id: Uuid,
+    queue: OutboundQueue,
+    expires_at: Instant,
+    cursor: u64,
+    unacknowledged: Vec<String>,
+}
+
+/// Outbound queue statistics for the connections on this node.
+pub struct QueueStats {
+    pub connections: usize,
+    pub queued_frames: usize,
+    pub max_queue_depth: usize,
+    pub dropped_frames: u64,
+    pub slow_consumer_disconnects: u64,
+}
+
+/// Delivers events to connected users. Connections are held locally, while events and the
+/// registry of which node holds which connection go through Redis so several nodes can
+/// serve the same users.
 #[derive(Clone)]
 pub struct WebSocketService {
     redis_client: RedisClient,
-    connections: Arc<RwLock<HashMap<Uuid, UserSender>>>,
+    node_id: Uuid,
+    publisher: Arc<Mutex<Option<MultiplexedConnection>>>,
+    connections: Arc<RwLock<HashMap<Uuid, HashMap<ConnectionId, OutboundQueue>>>>,
+    event_log_size: usize,
+    event_log_ttl: u64,
+    queue_capacity: usize,
+    queue_metrics: Arc<QueueMetrics>,
+    polls: Arc<std::sync::Mutex<HashMap<ConnectionId, PollConnection>>>,
+    // Sent while holding the `connections` lock, so they're ordered like its changes
+    subscriptions: mpsc::UnboundedSender<Subscription>,
+    subscription_changes: Arc<std::sync::Mutex<mpsc::UnboundedReceiver<Subscription>>>,
+    log_and_publish_script: redis::Script,
 }
 
 impl WebSocketService {
-    pub fn new(redis_client: RedisClient) -> Self {
+    pub fn new(redis_client: RedisClient, event_log_size: usize, event_log_ttl: u64, queue_capacity: usize) -> Self {
+        let (subscriptions, subscription_changes) = mpsc::unbounded_channel();
         Self {
             redis_client,
+            node_id: Uuid::new_v4(),
+            publisher: Arc::new(Mutex::new(None)),
             connections: Arc::new(RwLock::new(HashMap::new())),
+            event_log_size,
+            event_log_ttl,
+            queue_capacity,
+            queue_metrics: Arc::new(QueueMetrics::default()),
+            polls: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            subscriptions,
+            subscription_changes: Arc::new(std::sync::Mutex::new(subscription_changes)),
+            log_and_publish_script: redis::Script::new(LOG_AND_PUBLISH_SCRIPT),
         }
     }
 
-    pub async fn add_connection(&self, user_id: Uuid) -> UserReceiver {
-        let (tx, rx) = broadcast::channel(100);
+    /// Creates the outbound queue for a new socket.
+    pub fn new_queue(&self) -> OutboundQueue {
+        OutboundQueue::new(self.queue_capacity, self.queue_metrics.clone())
+    }
+
+    /// Registers a socket's queue to receive the user's events.
+    pub async fn add_connection(&self, user_id: Uuid, queue: OutboundQueue) -> UserConnection {
+        let connection_id = Uuid::new_v4();
+        
+        let (local_first, subscribed) = {
+            let mut connections = self.connections.write().await;
+            let user_connections = connections.entry(user_id).or_default();
+            user_connections.insert(connection_id, queue);
+            let local_first = user_connections.len() == 1;
+
+            let (ready, subscribed) = oneshot::channel();
+            if local_first {
+                let _ = self.subscriptions.send(Subscription::Subscribe(user_id, ready));
+            }
+            (local_first, subscribed)
+        };
+
+        // Events published before the channel is subscribed would be missed. If Redis is down
+        // this gives up, and the subscriber subscribes to every connected user once it's back.
+        if local_first {
+            let _ = tokio::time::timeout(SUBSCRIBE_TIMEOUT, subscribed).await;
+        }
+        
+        // The cluster-wide count decides presence; fall back to this node's view without Redis
+        let first = match self.register_connection(user_id, connection_id).await {
+            Ok(count) => count == 1,
+            Err(e) => {
+                warn!("Failed to register connection {} in Redis: {}", connection_id, e);
+                self.reset_redis().await;
+                local_first
+            }
+        };
         
-        let mut connections = self.connections.write().await;
-        connections.insert(user_id, tx);
+        UserConnection {
+            id: connection_id,
+            first,
+        }
+    }
+
+    /// Unregisters one connection. Returns true if it was the user's last one on any node.
+    pub async fn remove_connection(&self, user_id: Uuid, connection_id: ConnectionId) -> bool {
+        let local_last = {
+            let mut connections = self.connections.write().await;
+            
+            let Some(user_connections) = connections.get_mut(&user_id) else {
+                return false;
+            };
+            
+            user_connections.remove(&connection_id);
+            let last = user_connections.is_empty();
+            if last {
+                connections.remove(&user_id);
+                let _ = self.subscriptions.send(Subscription::Unsubscribe(user_id));
+            }
+            last
+        };
         
-        rx
+        match self.unregister_connection(user_id, connection_id).await {
+            Ok(count) => count == 0,
+            Err(e) => {
+                warn!("Failed to unregister connection {} in Redis: {}", connection_id, e);
+                self.reset_redis().await;
+                local_last
+            }
+        }
     }
 
-    pub async fn remove_connection(&self, user_id: Uuid) {
-        let mut connections = self.connections.write().await;
-        connections.remove(&user_id);
+    async fn register_connection(&self, user_id: Uuid, connection_id: ConnectionId) -> Result<i64> {
+        let mut conn = self.redis().await?;
+        let (count,): (i64,) = redis::pipe()
+            .atomic()
+            .sadd(NODES_KEY, self.node_id.to_string()).ignore()
+            .sadd(node_connections_key(self.node_id), format!("{}:{}", user_id, connection_id)).ignore()
+            .sadd(presence_key(user_id), format!("{}:{}", self.node_id, connection_id)).ignore()
+            .scard(presence_key(user_id))
+            .query_async(&mut conn)
+            .await?;
+        Ok(count)
     }
 
-    pub async fn send_to_user(&self, user_id: Uuid, message: &str) -> Result<()> {
-        let connections = self.connections.read().await;
-        
-        if let Some(sender) = connections.get(&user_id) {
-            let _ = sender.send(message.to_string());
+    async fn unregister_connection(&self, user_id: Uuid, connection_id: ConnectionId) -> Result<i64> {
+        let mut conn = self.redis().await?;
+        let (count,): (i64,) = redis::pipe()
+            .atomic()
+            .srem(node_connections_key(self.node_id), format!("{}:{}", user_id, connection_id)).ignore()
+            .srem(presence_key(user_id), format!("{}:{}", self.node_id, connection_id)).ignore()
+            .scard(presence_key(user_id))
+            .query_async(&mut conn)
+            .await?;
+        Ok(count)
+    }
+
+    /// Keeps a long-polling connection's queue on this node until `expires_at`.
+    pub fn hold_poll(&self, user_id: Uuid, connection_id: ConnectionId, queue: OutboundQueue, expires_at: Instant) {
+        let mut polls = self.polls.lock().unwrap_or_else(|e| e.into_inner());
+        polls.insert(
+            connection_id,
+            PollConnection {
+                user_id,
+                queue,
+                expires_at,
+                cursor: 0,
+                unacknowledged: Vec::new(),
+            },
+        );
+    }
+
+    /// Returns the queue of a long-polling connection held on this node and extends its
+    /// lease to `expires_at`, along with the frames of the last response unless `ack` is
+    /// its cursor. Without `ack` the last response counts as received.
+    pub fn poll_queue(
+        &self,
+        user_id: Uuid,
+        connection_id: ConnectionId,
+        expires_at: Instant,
+        ack: Option<u64>,
+    ) -> Option<(OutboundQueue, Vec<String>)> {
+        let mut polls = self.polls.lock().unwrap_or_else(|e| e.into_inner());
+        let poll = polls.get_mut(&connection_id).filter(|poll| poll.user_id == user_id)?;
+        poll.expires_at = poll.expires_at.max(expires_at);
+
+        let unacknowledged = std::mem::take(&mut poll.unacknowledged);
+        let resend = match ack {
+            Some(ack) if ack != poll.cursor => unacknowledged,
+            _ => Vec::new(),
+        };
+        Some((poll.queue.clone(), resend))
+    }
+
+    /// Keeps the frames of a response to a long poll until they're acknowledged, and
+    /// returns the cursor to acknowledge them with.
+    pub fn hand_out_poll(&self, connection_id: ConnectionId, frames: &[String]) -> u64 {
+        let mut polls = self.polls.lock().unwrap_or_else(|e| e.into_inner());
+        match polls.get_mut(&connection_id) {
+            Some(poll) => {
+                poll.cursor += 1;
+                poll.unacknowledged = frames.to_vec();
+                poll.cursor
+            }
+            None => 0,
         }
-        
+    }
+
+    /// Stops holding a long-polling connection, e.g. after its queue was closed.
+    pub fn release_poll(&self, connection_id: ConnectionId) {
+        self.polls.lock().unwrap_or_else(|e| e.into_inner()).remove(&connection_id);
+    }
+
+    /// Removes the long-polling connections whose lease ran out, returning them so they
+    /// can be disconnected.
+    pub fn expire_polls(&self) -> Vec<(Uuid, ConnectionId)> {
+        let now = Instant::now();
+        let mut polls = self.polls.lock().unwrap_or_else(|e| e.into_inner());
+        let mut expired = Vec::new();
+        polls.retain(|connection_id, poll| {
+            if poll.expires_at > now {
+                return true;
+            }
+            poll.queue.finish();
+            expired.push((poll.user_id, *connection_id));
+            false
+        });
+        expired
+    }
+
+    /// Marks this node alive for another `NODE_TTL_SECS`.
+    pub async fn heartbeat(&self) -> Result<()> {
+        let mut conn = self.redis().await?;
+        redis::pipe()
+            .sadd(NODES_KEY, self.node_id.to_string()).ignore()
+            .set_ex(node_alive_key(self.node_id), 1, NODE_TTL_SECS).ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
         Ok(())
     }
 
-    pub async fn broadcast_message(&self, message: &MessageResponse, chat_participants: &[Uuid]) -> Result<()> {
-        let ws_message = WebSocketMessage {
-            message_type: "new_message".to_string(),
-            data: serde_json::to_value(message)?,
-        };
+    /// Removes the connections of nodes whose heartbeat expired. Returns the users left
+    /// without any connection, who should now be reported offline.
+    pub async fn reap_dead_nodes(&self) -> Result<Vec<Uuid>> {
+        let mut conn = self.redis().await?;
+        let nodes: Vec<String> = conn.smembers(NODES_KEY).await?;
+        let mut offline = Vec::new();
         
-        let message_str = serde_json::to_string(&ws_message)?;
-        
-        for &user_id in chat_participants {
-            let _ = self.send_to_user(user_id, &message_str).await;
+        for node in nodes {
+            let Ok(node_id) = Uuid::parse_str(&node) else { continue };
+            if node_id == self.node_id || conn.exists(node_alive_key(node_id)).await? {
+                continue;
+            }
+            
+            info!("Reaping connections of dead node {}", node_id);
+            let members: Vec<String> = conn.smembers(node_connections_key(node_id)).await?;
+            for member in members {
+                let Some((user_id, connection_id)) = member.split_once(':') else { continue };
+                let Ok(user_id) = Uuid::parse_str(user_id) else { continue };
+                
+                let (count,): (i64,) = redis::pipe()
+                    .atomic()
+                    .srem(presence_key(user_id), format!("{}:{}", node_id, connection_id)).ignore()
+                    .scard(presence_key(user_id))
+                    .query_async(&mut conn)
+                    .await?;
+                if count == 0 && !offline.contains(&user_id) {
+                    offline.push(user_id);
+                }
+            }
+            
+            redis::pipe()
+                .del(node_connections_key(node_id)).ignore()
+                .srem(NODES_KEY, node).ignore()
+                .query_async::<_, ()>(&mut conn)
+                .await?;
         }
         
-        Ok(())
+        Ok(offline)
     }
 
-    pub async fn broadcast_typing(&self, typing: &TypingIndicator, chat_participants: &[Uuid]) -> Result<()> {
-        let ws_message = WebSocketMessage {
-            message_type: "typing".to_string(),
-            data: serde_json::to_value(typing)?,
+    /// Issues a single-use ticket that authenticates a WebSocket upgrade, for clients that
+    /// can't set headers on it.
+    pub async fn issue_ticket(&self, user_id: Uuid) -> Result<String> {
+        let ticket = Uuid::new_v4().simple().to_string();
+        let mut conn = self.redis().await?;
+        conn.set_ex::<_, _, ()>(ticket_key(&ticket), user_id.to_string(), TICKET_TTL_SECS).await?;
+        Ok(ticket)
+    }
+
+    /// Consumes a ticket and returns the user it was issued to, if it is still valid.
+    pub async fn redeem_ticket(&self, ticket: &str) -> Result<Option<Uuid>> {
+        let mut conn = self.redis().await?;
+        let (user_id,): (Option<String>,) = redis::pipe()
+            .atomic()
+            .get(ticket_key(ticket))
+            .del(ticket_key(ticket)).ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(user_id.and_then(|user_id| Uuid::parse_str(&user_id).ok()))
+    }
+
+    /// Number of connections each user has on any node.
+    pub async fn connection_counts(&self, user_ids: &[Uuid]) -> Result<Vec<i64>> {
+        let mut conn = self.redis().await?;
+        let mut pipe = redis::pipe();
+        for &user_id in user_ids {
+            pipe.scard(presence_key(user_id));
+        }
+        Ok(pipe.query_async(&mut conn).await?)
+    }
+
+    /// Users with a registered connection on any node.
+    pub async fn connected_user_ids(&self) -> Result<Vec<Uuid>> {
+        let mut conn = self.redis().await?;
+        let nodes: Vec<String> = conn.smembers(NODES_KEY).await?;
+        let mut user_ids = HashSet::new();
+
+        for node in nodes {
+            let Ok(node_id) = Uuid::parse_str(&node) else { continue };
+            let members: Vec<String> = conn.smembers(node_connections_key(node_id)).await?;
+            user_ids.extend(members.iter().filter_map(|member| {
+                let (user_id, _) = member.split_once(':')?;
+                Uuid::parse_str(user_id).ok()
+            }));
+        }
+
+        Ok(user_ids.into_iter().collect())
+    }
+
+    /// Claims a cluster-wide task for `ttl_secs`, so periodic work runs on one node at a
+    /// time. Returns false if another node holds it.
+    pub async fn try_lock(&self, name: &str, ttl_secs: u64) -> Result<bool> {
+        let mut conn = self.redis().await?;
+        let acquired: Option<String> = redis::cmd("SET")
+            .arg(format!("ws:lock:{}", name))
+            .arg(self.node_id.to_string())
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl_secs)
+            .query_async(&mut conn)
+            .await?;
+        Ok(acquired.is_some())
+    }
+
+    /// Receives events published by any node for the users connected to this one and
+    /// delivers them to their connections. Runs until the Redis subscription drops.
+    pub async fn run_subscriber(&self) -> Result<()> {
+        let service = self.clone();
+        let runtime = Handle::current();
+        tokio::task::spawn_blocking(move || service.subscribe_blocking(&runtime)).await?
+    }
+
+    // Uses the blocking client, which keeps the events that arrive while a subscription
+    // changes; the async one can't change subscriptions while reading events
+    fn subscribe_blocking(&self, runtime: &Handle) -> Result<()> {
+        let mut conn = self.redis_client.get_connection()?;
+        let mut pubsub = conn.as_pubsub();
+        pubsub.set_read_timeout(Some(SUBSCRIBER_POLL))?;
+
+        let mut changes = self.subscription_changes.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Start from the users connected now; changes queued before are covered by that
+        let user_ids: Vec<Uuid> = {
+            let connections = runtime.block_on(self.connections.read());
+            while changes.try_recv().is_ok() {}
+            connections.keys().copied().collect()
+        };
+        for user_id in user_ids {
+            pubsub.subscribe(user_channel(user_id))?;
+        }
+
+        loop {
+            while let Ok(change) = changes.try_recv() {
+                match change {
+                    Subscription::Subscribe(user_id, _ready) => pubsub.subscribe(user_channel(user_id))?,
+                    Subscription::Unsubscribe(user_id) => pubsub.unsubscribe(user_channel(user_id))?,
+                }
+            }
+
+            let msg = match pubsub.get_message() {
+                Ok(msg) => msg,
+                Err(e) if e.is_timeout() => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let Some(user_id) = msg
+                .get_channel_name()
+                .strip_prefix(USER_CHANNEL_PREFIX)
+                .and_then(|user_id| Uuid::parse_str(user_id).ok())
+            else {
+                continue;
+            };
+
+            let payload: String = msg.get_payload()?;
+            match serde_json::from_str::<Envelope>(&payload) {
+                Ok(envelope) => runtime.block_on(self.deliver_local(user_id, &envelope)),
+                Err(e) => warn!("Dropping malformed event for user {}: {}", user_id, e),
+            }
+        }
+    }
+
+    /// Sends to every device the user is connected from.
+    pub async fn send_to_user(&self, user_id: Uuid, message: &str) -> Result<()> {
+        self.send_to_user_except(user_id, None, message).await
+    }
+
+    /// Sends to every device of the user except the given connection, typically the one
+    /// that caused the event.
+    pub async fn send_to_user_except(&self, user_id: Uuid, except: Option<ConnectionId>, message: &str) -> Result<()> {
+        self.publish(user_id, Envelope { seq: None, only: None, except, payload: message.to_string(), ephemeral: false }).await
+    }
+
+    /// Sends to a single device.
+    pub async fn send_to_connection(&self, user_id: Uuid, connection_id: ConnectionId, message: &str) -> Result<()> {
+        self.publish(
+            user_id,
+            Envelope { seq: None, only: Some(connection_id), except: None, payload: message.to_string(), ephemeral: false },
+        )
+        .await
+    }
+
+    async fn publish(&self, user_id: Uuid, envelope: Envelope) -> Result<()> {
+        let channel = user_channel(user_id);
+        let result = match self.redis().await {
+            Ok(mut conn) => self.log_and_publish(&mut conn, user_id, &channel, &envelope).await,
+            Err(e) => Err(e),
         };
         
-        let message_str = serde_json::to_string(&ws_message)?;
+        // Without Redis at least the users connected to this node still get the event
+        if let Err(e) = result {
+            warn!("Failed to publish to {}, delivering locally: {}", channel, e);
+            self.reset_redis().await;
+            self.deliver_local(user_id, &envelope).await;
+        }
         
-        for &user_id in chat_participants {
-            if user_id != typing.user_id {
-                let _ = self.send_to_user(user_id, &message_str).await;
-            }
+        Ok(())
+    }
+
+    // Events for all of a user's devices are numbered and logged so a reconnecting device
+    // can replay them; replies to a single connection and ephemeral events are not
+    async fn log_and_publish(
+        &self,
+        conn: &mut MultiplexedConnection,
+        user_id: Uuid,
+        channel: &str,
+        envelope: &Envelope,
+    ) -> Result<()> {
+        if envelope.only.is_none() && !envelope.ephemeral {
+            self.log_and_publish_script
+                .key(seq_key(user_id))
+                .key(event_log_key(user_id))
+                .arg(&envelope.payload)
+                .arg(self.event_log_size)
+                .arg(self.event_log_ttl)
+                .arg(channel)
+                .arg(serde_json::to_string(envelope)?)
+                .invoke_async::<_, i64>(conn)
+                .await?;
+            return Ok(());
         }
         
+        conn.publish::<_, _, ()>(channel, serde_json::to_string(envelope)?).await?;
         Ok(())
     }
 
-    pub async fn broadcast_user_status(&self, user_id: Uuid, is_online: bool) -> Result<()> {
-        let status_message = WebSocketMessage {
-            message_type: "user_status".to_string(),
-            data: serde_json::json!({
-                "user_id": user_id,
-                "is_online": is_online
-            }),
-        };
+    /// Sequence number of the latest event sent to the user.
+    pub async fn current_seq(&self, user_id: Uuid) -> Result<i64> {
+        let mut conn = self.redis().await?;
+        let seq: Option<i64> = conn.get(seq_key(user_id)).await?;
+        Ok(seq.unwrap_or(0))
+    }
+
+    /// Returns the user's logged events with a sequence number above `last_seq`.
+    pub async fn replay(&self, user_id: Uuid, last_seq: i64) -> Result<Replay> {
+        let mut conn = self.redis().await?;
+        let current_seq = self.current_seq(user_id).await?;
+        
+        if last_seq >= current_seq {
+            return Ok(Replay {
+                frames: Vec::new(),
+                current_seq,
+                complete: last_seq == current_seq,
+            });
+        }
+        
+        let entries: Vec<(String, i64)> = conn
+            .zrangebyscore_withscores(event_log_key(user_id), format!("({}", last_seq), "+inf")
+            .await?;
         
-        let message_str = serde_json::to_string(&status_message)?;
+        // The log was trimmed or expired past the client's position
+        let complete = entries.first().is_some_and(|(_, seq)| *seq == last_seq + 1);
         
-        // Get user's friends and group members to notify
-        let mut redis_conn = self.redis_client.get_connection()?;
-        let friends_key = format!("user:{}:friends", user_id);
-        let friends: Vec<String> = redis_conn.smembers(&friends_key)?;
+        Ok(Replay {
+            frames: entries.into_iter().map(|(frame, _)| frame).collect(),
+            current_seq,
+            complete,
+        })
+    }
+
+    async fn deliver_local(&self, user_id: Uuid, envelope: &Envelope) {
+        let connections = self.connections.read().await;
         
-        for friend_id_str in friends {
-            if let Ok(friend_id) = Uuid::parse_str(&friend_id_str) {
-                let _ = self.send_to_user(friend_id, &message_str).await;
+        if let Some(user_connections) = connections.get(&user_id) {
+            let kind = if envelope.ephemeral { FrameKind::Ephemeral } else { FrameKind::Event };
+            let payload = match envelope.seq {
+                Some(seq) => with_seq(&envelope.payload, seq),
+                None => envelope.payload.clone(),
+            };
+            for (connection_id, queue) in user_connections {
+                if envelope.only.is_some_and(|only| only != *connection_id) || envelope.except == Some(*connection_id) {
+                    continue;
+                }
+                if !queue.push(payload.clone(), kind) {
+                    warn!("Dropped event for closing connection {}", connection_id);
+                }
             }
         }
+    }
+
+    // Shared connection for publishing and registry updates, reopened after a failure
+    async fn redis(&self) -> Result<MultiplexedConnection> {
+        let mut publisher = self.publisher.lock().await;
+        if let Some(conn) = publisher.as_ref() {
+            return Ok(conn.clone());
+        }
+        
+        let conn = self.redis_client.get_multiplexed_tokio_connection().await?;
+        *publisher = Some(conn.clone());
+        Ok(conn)
+    }
+
+    async fn reset_redis(&self) {
+        *self.publisher.lock().await = None;
+    }
+
+    /// Sends an event to every device of each recipient.
+    pub async fn broadcast(&self, event: ServerEvent<'_>, recipients: &[Uuid]) -> Result<()> {
+        let ephemeral = event.is_ephemeral();
+        let message_str = encode(event)?;
+        
+        for &user_id in recipients {
+            let envelope = Envelope { seq: None, only: None, except: None, payload: message_str.clone(), ephemeral };
+            let _ = self.publish(user_id, envelope).await;
+        }
         
         Ok(())
     }
 
-    pub async fn cache_user_friends(&self, user_id: Uuid, friend_ids: &[Uuid]) -> Result<()> {
-        let mut redis_conn = self.redis_client.get_connection()?;
-        let friends_key = format!("user:{}:friends", user_id);
+    pub async fn broadcast_message(&self, message: &MessageResponse, chat_participants: &[Uuid]) -> Result<()> {
+        self.broadcast(ServerEvent::NewMessage(message), chat_participants).await
+    }
+
+    /// Delivers a thread reply to the thread's followers and the sender, and a
+    /// `thread_updated` summary to the rest of the chat.
+    pub async fn broadcast_thread_reply(
+        &self,
+        message: &MessageResponse,
+        update: &ThreadUpdated,
+        followers: &[Uuid],
+        chat_participants: &[Uuid],
+    ) -> Result<()> {
+        let (recipients, others): (Vec<Uuid>, Vec<Uuid>) = chat_participants
+            .iter()
+            .partition(|&&user_id| user_id == message.sender.id || followers.contains(&user_id));
+
+        self.broadcast_message(message, &recipients).await?;
+        self.broadcast(ServerEvent::ThreadUpdated(update), &others).await
+    }
+
+    pub async fn broadcast_typing(&self, typing: &TypingIndicator, chat_participants: &[Uuid]) -> Result<()> {
+        let recipients: Vec<Uuid> = chat_participants
+            .iter()
+            .copied()
+            .filter(|&user_id| user_id != typing.user_id)
+            .collect();
+        self.broadcast(ServerEvent::Typing(typing), &recipients).await
+    }
+
+    pub async fn queue_stats(&self) -> QueueStats {
+        let connections = self.connections.read().await;
+        let depths: Vec<usize> = connections
+            .values()
+            .flat_map(|user_connections| user_connections.values().map(OutboundQueue::depth))
+            .collect();
         
-        // Clear existing friends
-        let _: () = redis_conn.del(&friends_key)?;
+        QueueStats {
+            connections: depths.len(),
+            queued_frames: depths.iter().sum(),
+            max_queue_depth: depths.iter().copied().max().unwrap_or(0),
+            dropped_frames: self.queue_metrics.dropped_frames.load(Ordering::Relaxed),
+            slow_consumer_disconnects: self.queue_metrics.slow_consumer_disconnects.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sends a `read_receipt` or `delivery_receipt` event to everyone in the chat, including
+    /// the author's other devices so they can clear their unread state. The connection the
+    /// receipt came from, if any, is skipped.
+    pub async fn broadcast_receipt(
+        &self,
+        read: bool,
+        receipt: &MessageReceipt,
+        chat_participants: &[Uuid],
+        origin: Option<ConnectionId>,
+    ) -> Result<()> {
+        let event = if read {
+            ServerEvent::ReadReceipt(receipt)
+        } else {
+            ServerEvent::DeliveryReceipt(receipt)
+        };
+        let message_str = encode(event)?;
         
-        // Add current friends
-        if !friend_ids.is_empty() {
-            let friend_strings: Vec<String> = friend_ids.iter().map(|id| id.to_string()).collect();
-            let _: () = redis_conn.sadd(&friends_key, friend_strings)?;
+        for &user_id in chat_participants {
+            if user_id == receipt.user_id {
+                let _ = self.send_to_user_except(user_id, origin, &message_str).await;
+            } else {
+                let _ = self.send_to_user(user_id, &message_str).await;
+            }
         }
         
         Ok(())
     }
+
+}
+
+/// Serializes an event that isn't a reply to a request.
+pub fn encode(event: ServerEvent<'_>) -> Result<String> {
+    Ok(serde_json::to_string(&ServerFrame::from(event))?)
+}
+
+// Adds the sequence number to a serialized frame, which is always a JSON object
+fn with_seq(frame: &str, seq: i64) -> String {
+    format!("{{\"seq\":{},{}", seq, &frame[1..])
+}
+
+fn user_channel(user_id: Uuid) -> String {
+    format!("{}{}", USER_CHANNEL_PREFIX, user_id)
+}
+
+fn seq_key(user_id: Uuid) -> String {
+    format!("ws:seq:{}", user_id)
+}
+
+fn event_log_key(user_id: Uuid) -> String {
+    format!("ws:events:{}", user_id)
+}
+
+fn ticket_key(ticket: &str) -> String {
+    format!("ws:ticket:{}", ticket)
+}
+
+fn presence_key(user_id: Uuid) -> String {
+    format!("ws:presence:{}", user_id)
+}
+
+fn node_connections_key(node_id: Uuid) -> String {
+    format!("ws:node:{}:connections", node_id)
+}
+
+fn node_alive_key(node_id: Uuid) -> String {
+    format!("ws:node:{}:alive", node_id)
 }
@@ -1,29 +1,363 @@
-use axum::extract::ws::{Message, WebSocket};
-use futures_util::{sink::SinkExt, stream::StreamExt};
-use serde_json;
-use tokio::select;
+use axum::{
+    extract::ws::{close_code, CloseFrame, Message, WebSocket},
+    http::{
+        header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL},
+        HeaderMap, StatusCode,
+    },
+};
+use futures_util::{
+    sink::SinkExt,
+    stream::{SplitSink, StreamExt},
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::{select, time::Instant};
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
 use crate::{
-    models::{SendMessageRequest, TypingIndicator, WebSocketMessage},
+    handlers::{messages::message_error_status, users::user_error_status},
+    models::{
+        AuthRequest, Authenticated, ClientFrame, ClientRequest, EditMessageRequest, ErrorFrame, MessageEdited, MessageResponse,
+        Resumed, ServerEvent, ServerFrame, TypingIndicator, UserStatus, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    services::{
+        codec::{Encoding, FrameCodec, FrameCompression, WireFrame},
+        message::MessageError,
+        user::UserError,
+        outbound::{FrameKind, Outbound, OutboundQueue, SLOW_CONSUMER_CLOSE_CODE},
+        rate_limit::{ConnectionBuckets, FrameClass},
+        websocket::{ConnectionId, UserConnection, NODE_TTL_SECS},
+    },
     AppState,
 };
 
-pub async fn handle_socket(socket: WebSocket, state: AppState) {
-    let (mut sender, mut receiver) = socket.split();
-    let mut user_id: Option<Uuid> = None;
-    let mut ws_receiver: Option<tokio::sync::broadcast::Receiver<String>> = None;
+/// Subprotocol selected for clients that authenticate with a `bearer.<token>` subprotocol.
+/// Browsers require the server to select one of the offered subprotocols.
+pub const SUBPROTOCOL: &str = "rusty-chat";
+const BEARER_SUBPROTOCOL_PREFIX: &str = "bearer.";
+
+/// How long a closing connection gets to flush its queue.
+const WRITER_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds between presence sweeps, which correct users left online without a connection
+/// and clear expired custom statuses.
+const PRESENCE_SWEEP_SECS: u64 = 60;
+
+/// Rate-limited frames in a row after which a connection is considered a flood and closed.
+const MAX_RATE_LIMIT_STRIKES: u32 = 20;
+
+/// Errors in the WebSocket exchange itself, as opposed to the request being made.
+#[derive(Debug, Error)]
+enum ProtocolError {
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
+    #[error("Authenticate before sending requests")]
+    Unauthenticated,
+    #[error("Invalid or expired access token")]
+    InvalidToken,
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Too many requests, retry in {} ms", .0.as_millis())]
+    RateLimited(Duration),
+}
+
+/// Query parameters of the upgrade request.
+#[derive(Debug, Deserialize)]
+pub struct UpgradeQuery {
+    pub ticket: Option<String>,
+    pub protocol_version: Option<u32>,
+    pub encoding: Option<Encoding>,
+    pub frame_compression: Option<FrameCompression>,
+}
+
+impl UpgradeQuery {
+    /// Wire format requested for the connection, JSON text frames by default.
+    pub fn codec(&self) -> FrameCodec {
+        FrameCodec {
+            encoding: self.encoding.unwrap_or_default(),
+            compression: self.frame_compression.unwrap_or_default(),
+        }
+    }
+}
+
+/// A user authenticated during the upgrade.
+pub struct UpgradeAuth {
+    user_id: Uuid,
+    protocol_version: u32,
+}
+
+/// A chat the user is typing in, as relayed to its participants.
+struct TypingState {
+    chat_id: Uuid,
+    participants: Vec<Uuid>,
+    relayed_at: Instant,
+    expires_at: Instant,
+}
+
+/// State of one WebSocket connection.
+struct Session {
+    user_id: Option<Uuid>,
+    username: String,
+    connection_id: Option<ConnectionId>,
+    protocol_version: u32,
+    codec: FrameCodec,
+    queue: OutboundQueue,
+    // Set when this connection brought the user online, until that has been announced
+    announce_online: bool,
+    rate_buckets: ConnectionBuckets,
+    rate_limit_strikes: u32,
+    // Keyed by the chat id the client sent
+    typing: HashMap<Uuid, TypingState>,
+}
+
+impl Session {
+    fn new(codec: FrameCodec, queue: OutboundQueue, rate_buckets: ConnectionBuckets) -> Self {
+        Self {
+            user_id: None,
+            username: String::new(),
+            connection_id: None,
+            protocol_version: MIN_PROTOCOL_VERSION,
+            codec,
+            queue,
+            announce_online: false,
+            rate_buckets,
+            rate_limit_strikes: 0,
+            typing: HashMap::new(),
+        }
+    }
+
+    fn user_id(&self) -> anyhow::Result<Uuid> {
+        Ok(self.user_id.ok_or(ProtocolError::Unauthenticated)?)
+    }
+}
+
+/// Starts the background tasks that join this node to the cluster: delivering events
+/// published by any node to local connections, the heartbeat that lets nodes clean
+/// up after a peer that stopped without unregistering its connections, and the
+/// presence sweeper.
+pub fn spawn_cluster_tasks(state: AppState) {
+    let websocket = state.services.websocket.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = websocket.run_subscriber().await {
+                error!("Redis subscription failed: {}", e);
+            }
+            warn!("Redis subscription ended, resubscribing");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+
+    let sweeper_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(PRESENCE_SWEEP_SECS));
+        loop {
+            interval.tick().await;
+
+            // One node sweeps per interval; the lock expires just before the next tick
+            match sweeper_state.services.websocket.try_lock("presence_sweep", PRESENCE_SWEEP_SECS - 1).await {
+                Ok(true) => {
+                    if let Err(e) = sweep_presence(&sweeper_state).await {
+                        warn!("Presence sweep failed: {}", e);
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => warn!("Failed to acquire presence sweep lock: {}", e),
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(NODE_TTL_SECS / 3));
+        loop {
+            interval.tick().await;
+            state.services.rate_limit.prune();
+            for (uid, cid) in state.services.websocket.expire_polls() {
+                disconnect(&state, uid, cid).await;
+            }
+
+            if let Err(e) = state.services.websocket.heartbeat().await {
+                warn!("Node heartbeat failed: {}", e);
+                continue;
+            }
+
+            match state.services.websocket.reap_dead_nodes().await {
+                Ok(offline) => {
+                    for uid in offline {
+                        set_offline(&state, uid).await;
+                    }
+                }
+                Err(e) => warn!("Failed to reap dead nodes: {}", e),
+            }
+        }
+    });
+}
+
+async fn sweep_presence(state: &AppState) -> anyhow::Result<()> {
+    // Users marked online without a registered connection, e.g. left over from a
+    // failed update on disconnect or from before connections were registered
+    let online = state.services.user.get_online_user_ids().await?;
+    if !online.is_empty() {
+        let counts = state.services.websocket.connection_counts(&online).await?;
+        for (uid, count) in online.into_iter().zip(counts) {
+            if count == 0 {
+                info!("Marking stale user {} offline", uid);
+                set_offline(state, uid).await;
+            }
+        }
+    }
+
+    // And users marked offline while connected, e.g. by a disconnect that raced a reconnect
+    let connected = state.services.websocket.connected_user_ids().await?;
+    for uid in state.services.user.get_offline_user_ids(&connected).await? {
+        info!("Marking connected user {} online", uid);
+        if let Err(e) = state.services.user.update_online_status(uid, true).await {
+            warn!("Failed to mark user {} online: {}", uid, e);
+            continue;
+        }
+        if let Err(e) = fan_out_presence(state, uid).await {
+            warn!("Failed to send presence of user {}: {}", uid, e);
+        }
+    }
+
+    for uid in state.services.user.clear_expired_status_text().await? {
+        if let Err(e) = fan_out_presence(state, uid).await {
+            warn!("Failed to send presence of user {}: {}", uid, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Authenticates an upgrade request from, in order of preference, an `Authorization:
+/// Bearer` header, a `bearer.<token>` subprotocol or a `ticket` query parameter. Returns
+/// `None` for a legacy client that will send an `auth` frame instead.
+pub async fn authenticate_upgrade(
+    state: &AppState,
+    headers: &HeaderMap,
+    query: &UpgradeQuery,
+) -> Result<Option<UpgradeAuth>, StatusCode> {
+    let protocol_version = query.protocol_version.unwrap_or(PROTOCOL_VERSION);
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let subprotocol = headers
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| {
+            header
+                .split(',')
+                .find_map(|protocol| protocol.trim().strip_prefix(BEARER_SUBPROTOCOL_PREFIX))
+        });
+
+    let token = bearer_token(headers).or(subprotocol);
+    match authenticate_credentials(state, token, query.ticket.as_deref()).await? {
+        Some(user_id) => Ok(Some(UpgradeAuth { user_id, protocol_version })),
+        None if state.config.ws_legacy_auth => Ok(None),
+        None => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// The token of an `Authorization: Bearer` header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+}
+
+/// Authenticates an access token or, failing that, a single-use ticket. Returns `None`
+/// when neither was given.
+pub async fn authenticate_credentials(
+    state: &AppState,
+    token: Option<&str>,
+    ticket: Option<&str>,
+) -> Result<Option<Uuid>, StatusCode> {
+    if let Some(token) = token {
+        return state.services.auth.verify_access_token(token).map(Some).map_err(|_| StatusCode::UNAUTHORIZED);
+    }
+
+    match ticket {
+        Some(ticket) => match state.services.websocket.redeem_ticket(ticket).await {
+            Ok(Some(user_id)) => Ok(Some(user_id)),
+            Ok(None) => Err(StatusCode::UNAUTHORIZED),
+            Err(e) => {
+                error!("Failed to redeem WebSocket ticket: {}", e);
+                Err(StatusCode::SERVICE_UNAVAILABLE)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+pub async fn handle_socket(socket: WebSocket, state: AppState, upgrade_auth: Option<UpgradeAuth>, codec: FrameCodec) {
+    let (sender, mut receiver) = socket.split();
+    let mut session = Session::new(
+        codec,
+        state.services.websocket.new_queue(),
+        state.services.rate_limit.connection_buckets(),
+    );
+
+    // Writing happens on its own task so a slow client doesn't hold up reading its requests
+    let mut writer = tokio::spawn(write_outbound(sender, session.queue.clone(), codec));
+
+    if let Some(auth) = upgrade_auth {
+        match authenticate(&mut session, &state, auth.user_id, auth.protocol_version).await {
+            Ok(authenticated) => reply(&session, ServerEvent::Authenticated(authenticated)),
+            Err(e) => {
+                reply(&session, ServerEvent::Error(error_frame(Some("auth".to_string()), e)));
+                session.queue.close(close_code::POLICY, "Authentication failed");
+            }
+        }
+        announce_online(&mut session, &state).await;
+    }
+
+    // Legacy clients authenticate with an `auth` frame, which they must send in time
+    let auth_deadline = tokio::time::sleep(Duration::from_secs(state.config.ws_auth_timeout));
+    tokio::pin!(auth_deadline);
+
+    // Clients answer pings automatically; one that sends nothing at all for the timeout is gone
+    let ping_timeout = Duration::from_secs(state.config.ws_ping_timeout);
+    let ping_period = Duration::from_secs(state.config.ws_ping_interval);
+    let mut ping_interval = tokio::time::interval_at(Instant::now() + ping_period, ping_period);
+    let mut last_received = Instant::now();
+
+    let mut typing_interval = tokio::time::interval(Duration::from_secs(1));
+    typing_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
     loop {
         select! {
+            // The writer stops once the socket breaks or the queue closed the connection
+            _ = &mut writer => break,
+            _ = &mut auth_deadline, if session.user_id.is_none() => {
+                info!("WebSocket connection closed without authenticating");
+                session.queue.close(close_code::POLICY, "Authentication timed out");
+            }
+            _ = ping_interval.tick() => {
+                if last_received.elapsed() > ping_timeout {
+                    info!("WebSocket connection timed out");
+                    break;
+                }
+                session.queue.ping();
+            }
+            _ = typing_interval.tick(), if !session.typing.is_empty() => {
+                expire_typing(&mut session, &state).await;
+            }
             msg = receiver.next() => {
+                last_received = Instant::now();
                 match msg {
+                    // Text frames are always JSON; binary ones use the negotiated format
                     Some(Ok(Message::Text(text))) => {
-                        if let Err(e) = handle_text_message(&text, &mut user_id, &mut ws_receiver, &state).await {
-                            error!("Error handling text message: {}", e);
-                            break;
-                        }
+                        let value = serde_json::from_str(&text).map_err(|e| e.to_string());
+                        receive_frame(value, &mut session, &state).await;
+                    }
+                    Some(Ok(Message::Binary(data))) => {
+                        let value = session.codec.decode(&data).map_err(|e| e.to_string());
+                        receive_frame(value, &mut session, &state).await;
                     }
                     Some(Ok(Message::Close(_))) => {
                         info!("WebSocket connection closed");
@@ -37,88 +371,508 @@ pub async fn handle_socket(socket: WebSocket, state: AppState) {
                     _ => {}
                 }
             }
-            broadcast_msg = async {
-                if let Some(ref mut rx) = ws_receiver {
-                    rx.recv().await
-                } else {
-                    std::future::pending().await
+        }
+    }
+
+    // Cleanup on disconnect; the user stays online while another device is connected
+    stop_all_typing(&mut session, &state).await;
+    if let (Some(uid), Some(cid)) = (session.user_id, session.connection_id) {
+        disconnect(&state, uid, cid).await;
+    }
+
+    // Let the writer flush what's queued, such as a close frame, unless the client stopped reading
+    session.queue.finish();
+    if !writer.is_finished() && tokio::time::timeout(WRITER_DRAIN_TIMEOUT, &mut writer).await.is_err() {
+        writer.abort();
+    }
+}
+
+async fn write_outbound(mut sender: SplitSink<WebSocket, Message>, queue: OutboundQueue, codec: FrameCodec) {
+    while let Some(item) = queue.next().await {
+        let (message, last) = match item {
+            Outbound::Frame(frame) => match codec.encode(frame) {
+                Ok(WireFrame::Text(text)) => (Message::Text(text), false),
+                Ok(WireFrame::Binary(data)) => (Message::Binary(data), false),
+                Err(e) => {
+                    error!("Failed to encode WebSocket frame: {}", e);
+                    continue;
                 }
-            } => {
-                match broadcast_msg {
-                    Ok(msg) => {
-                        if let Err(e) = sender.send(Message::Text(msg)).await {
-                            error!("Error sending broadcast message: {}", e);
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        warn!("Broadcast receiver error: {}", e);
-                    }
+            },
+            Outbound::Ping => (Message::Ping(Vec::new()), false),
+            Outbound::Close(code, reason) => {
+                if code == SLOW_CONSUMER_CLOSE_CODE {
+                    warn!("Disconnecting slow WebSocket client");
                 }
+                (Message::Close(Some(CloseFrame { code, reason: reason.into() })), true)
             }
+        };
+
+        if let Err(e) = sender.send(message).await {
+            error!("Error sending WebSocket frame: {}", e);
+            break;
+        }
+        if last {
+            break;
+        }
+    }
+}
+
+async fn receive_frame(value: Result<Value, String>, session: &mut Session, state: &AppState) {
+    // Failed requests are answered with an error frame; only a broken socket ends the loop
+    for frame in handle_frame(value, session, state).await {
+        session.queue.push(frame, FrameKind::Reply);
+    }
+    announce_online(session, state).await;
+
+    if session.rate_limit_strikes >= MAX_RATE_LIMIT_STRIKES {
+        warn!("Closing WebSocket connection that keeps exceeding rate limits");
+        session.queue.close(close_code::POLICY, "Rate limit exceeded");
+    }
+}
+
+// Runs once the auth reply is queued, so it reaches the client before the user's presence
+async fn announce_online(session: &mut Session, state: &AppState) {
+    if !std::mem::take(&mut session.announce_online) {
+        return;
+    }
+    if let Some(uid) = session.user_id {
+        if let Err(e) = fan_out_presence(state, uid).await {
+            warn!("Failed to send presence of user {}: {}", uid, e);
+        }
+    }
+}
+
+fn reply(session: &Session, event: ServerEvent<'_>) {
+    if let Ok(frame) = serde_json::to_string(&ServerFrame::from(event)) {
+        session.queue.push(frame, FrameKind::Reply);
+    }
+}
+
+/// Unregisters a connection, marking the user offline when it was their last.
+pub async fn disconnect(state: &AppState, user_id: Uuid, connection_id: ConnectionId) {
+    if state.services.websocket.remove_connection(user_id, connection_id).await {
+        set_offline(state, user_id).await;
+    }
+}
+
+async fn set_offline(state: &AppState, user_id: Uuid) {
+    // The user may have reconnected since their last connection closed
+    match state.services.websocket.connection_counts(&[user_id]).await.as_deref() {
+        Ok([count]) if *count > 0 => return,
+        Ok(_) => {}
+        Err(e) => warn!("Failed to count connections of user {}: {}", user_id, e),
+    }
+
+    if let Err(e) = state.services.user.update_online_status(user_id, false).await {
+        warn!("Failed to mark user {} offline: {}", user_id, e);
+        return;
+    }
+    if let Err(e) = fan_out_presence(state, user_id).await {
+        warn!("Failed to send presence of user {}: {}", user_id, e);
+    }
+}
+
+/// Handles one decoded client frame and returns the frames to reply with: anything the
+/// request produced for this connection only, followed by its `ack` or `error` frame.
+async fn handle_frame(value: Result<Value, String>, session: &mut Session, state: &AppState) -> Vec<String> {
+    // Read the envelope first so even a malformed request gets its id echoed back
+    let value = match value {
+        Ok(value) => value,
+        Err(e) => {
+            let err = match check_rate_limit(session, state, None) {
+                Ok(()) => ProtocolError::InvalidFrame(e).into(),
+                Err(err) => err,
+            };
+            return error_reply(None, None, err);
+        }
+    };
+    let request_id = value.get("request_id").and_then(Value::as_str).map(str::to_string);
+    let request_type = value.get("message_type").and_then(Value::as_str).map(str::to_string);
+
+    if let Err(e) = check_rate_limit(session, state, request_type.as_deref()) {
+        return error_reply(request_id.as_deref(), request_type, e);
+    }
+
+    let frame: ClientFrame = match serde_json::from_value(value) {
+        Ok(frame) => frame,
+        Err(e) => {
+            return error_reply(request_id.as_deref(), request_type, ProtocolError::InvalidFrame(e.to_string()).into())
+        }
+    };
+
+    let request_id = frame.request_id;
+    let request_type = frame.request.message_type();
+    // Answers to these carry state the client needs even without a request id
+    let always_ack = matches!(frame.request, ClientRequest::Auth(_) | ClientRequest::Resume(_));
+    let mut replies = Vec::new();
+    match handle_request(frame.request, session, state, &mut replies).await {
+        // Version 1 clients don't expect acks
+        Ok(data) if session.protocol_version >= 2 && (request_id.is_some() || always_ack) => {
+            let reply = ServerFrame {
+                request_id: request_id.as_deref(),
+                event: ServerEvent::Ack(data),
+            };
+            replies.extend(serde_json::to_string(&reply).ok());
+            replies
+        }
+        Ok(_) => replies,
+        Err(e) => {
+            warn!("WebSocket request {} failed: {}", request_type, e);
+            error_reply(request_id.as_deref(), Some(request_type.to_string()), e)
         }
     }
+}
 
-    // Cleanup on disconnect
-    if let Some(uid) = user_id {
-        let _ = state.services.websocket.remove_connection(uid).await;
-        let _ = state.services.user.update_online_status(uid, false).await;
-        let _ = state.services.websocket.broadcast_user_status(uid, false).await;
+/// Takes a token for a frame of the given type, counting the frames rejected in a row.
+fn check_rate_limit(session: &mut Session, state: &AppState, request_type: Option<&str>) -> anyhow::Result<()> {
+    let class = FrameClass::of(request_type);
+    match state.services.rate_limit.check(&mut session.rate_buckets, session.user_id, class) {
+        Ok(()) => {
+            session.rate_limit_strikes = 0;
+            Ok(())
+        }
+        Err(retry_after) => {
+            session.rate_limit_strikes += 1;
+            Err(ProtocolError::RateLimited(retry_after).into())
+        }
     }
 }
 
-async fn handle_text_message(
-    text: &str,
-    user_id: &mut Option<Uuid>,
-    ws_receiver: &mut Option<tokio::sync::broadcast::Receiver<String>>,
+/// Performs a client request and returns the data to acknowledge it with. Frames meant
+/// for this connection only are pushed to `replies`.
+async fn handle_request(
+    request: ClientRequest,
+    session: &mut Session,
     state: &AppState,
-) -> anyhow::Result<()> {
-    let ws_message: WebSocketMessage = serde_json::from_str(text)?;
-
-    match ws_message.message_type.as_str() {
-        "auth" => {
-            let token: String = serde_json::from_value(ws_message.data)?;
-            let uid = state.services.auth.verify_access_token(&token)?;
-            
-            *user_id = Some(uid);
-            *ws_receiver = Some(state.services.websocket.add_connection(uid).await);
-            
-            // Update user online status
-            state.services.user.update_online_status(uid, true).await?;
-            state.services.websocket.broadcast_user_status(uid, true).await?;
-            
-            info!("User {} authenticated via WebSocket", uid);
-        }
-        "send_message" => {
-            if let Some(uid) = user_id {
-                let request: SendMessageRequest = serde_json::from_value(ws_message.data)?;
-                
-                // Send message through message service
-                if let Ok(message) = state.services.message.send_message(*uid, request).await {
-                    // Get chat participants
-                    let participants = state.services.message.get_chat_participants(message.chat_id).await?;
-                    
-                    // Broadcast to all participants
-                    state.services.websocket.broadcast_message(&message, &participants).await?;
+    replies: &mut Vec<String>,
+) -> anyhow::Result<Value> {
+    let data = match request {
+        ClientRequest::Auth(auth) => {
+            let (token, protocol_version) = match auth {
+                AuthRequest::Token(token) => (token, MIN_PROTOCOL_VERSION),
+                AuthRequest::Negotiate { token, protocol_version } => (token, protocol_version),
+            };
+            if protocol_version < MIN_PROTOCOL_VERSION {
+                return Err(ProtocolError::UnsupportedVersion(protocol_version).into());
+            }
+
+            let uid = state
+                .services
+                .auth
+                .verify_access_token(&token)
+                .map_err(|_| ProtocolError::InvalidToken)?;
+
+            serde_json::to_value(authenticate(session, state, uid, protocol_version).await?)?
+        }
+        ClientRequest::Resume(request) => {
+            let replay = state.services.websocket.replay(session.user_id()?, request.last_seq).await?;
+
+            // A partial replay would leave gaps, so the client resyncs instead
+            let replayed = if replay.complete { replay.frames.len() } else { 0 };
+            if replay.complete {
+                replies.extend(replay.frames);
+            }
+
+            serde_json::to_value(Resumed {
+                replayed,
+                current_seq: replay.current_seq,
+                resync_required: !replay.complete,
+            })?
+        }
+        ClientRequest::SendMessage(request) => {
+            let message = state.services.message.send_message(session.user_id()?, request).await?;
+            fan_out_message(state, &message).await?;
+
+            // Sending ends the sender's typing indicator in that chat
+            let typing_key = session
+                .typing
+                .iter()
+                .find(|(_, typing)| typing.chat_id == message.chat_id)
+                .map(|(chat_id, _)| *chat_id);
+            if let Some(chat_id) = typing_key {
+                stop_typing(session, state, chat_id).await;
+            }
+            serde_json::to_value(&message)?
+        }
+        ClientRequest::Typing(request) => {
+            let uid = session.user_id()?;
+            if request.is_typing {
+                start_typing(session, state, uid, request.chat_id).await?;
+            } else {
+                stop_typing(session, state, request.chat_id).await;
+            }
+            Value::Null
+        }
+        ClientRequest::EditMessage(event) => {
+            let request = EditMessageRequest { content: event.content };
+            let message = state.services.message.edit_message(session.user_id()?, event.message_id, request).await?;
+
+            let participants = state.services.message.get_chat_participants(message.chat_id).await?;
+            state.services.websocket.broadcast(ServerEvent::MessageEdited(&MessageEdited::from(&message)), &participants).await?;
+            serde_json::to_value(&message)?
+        }
+        ClientRequest::DeleteMessage(request) => {
+            let uid = session.user_id()?;
+            let (deleted, released_file) = state
+                .services
+                .message
+                .delete_message(uid, request.message_id, request.for_everyone)
+                .await?;
+
+            if let Some(file_id) = released_file {
+                if let Err(e) = state.services.file.release_file(file_id).await {
+                    warn!("Failed to release file {}: {}", file_id, e);
                 }
             }
+
+            let recipients = if deleted.for_everyone {
+                state.services.message.get_chat_participants(deleted.chat_id).await?
+            } else {
+                vec![uid]
+            };
+            state.services.websocket.broadcast(ServerEvent::MessageDeleted(&deleted), &recipients).await?;
+            serde_json::to_value(&deleted)?
+        }
+        ClientRequest::AddReaction(event) => {
+            let reaction = state.services.message.add_reaction(session.user_id()?, event.message_id, event.reaction).await?;
+
+            let participants = state.services.message.get_chat_participants(reaction.chat_id).await?;
+            state.services.websocket.broadcast(ServerEvent::MessageReaction(&reaction), &participants).await?;
+            serde_json::to_value(&reaction)?
         }
-        "typing" => {
-            if let Some(uid) = user_id {
-                let mut typing: TypingIndicator = serde_json::from_value(ws_message.data)?;
-                typing.user_id = *uid;
-                
-                // Get chat participants
-                let participants = state.services.message.get_chat_participants(typing.chat_id).await?;
-                
-                // Broadcast typing indicator
-                state.services.websocket.broadcast_typing(&typing, &participants).await?;
+        ClientRequest::RemoveReaction(event) => {
+            let reaction = state.services.message.remove_reaction(session.user_id()?, event.message_id, event.reaction).await?;
+
+            let participants = state.services.message.get_chat_participants(reaction.chat_id).await?;
+            state.services.websocket.broadcast(ServerEvent::MessageReaction(&reaction), &participants).await?;
+            serde_json::to_value(&reaction)?
+        }
+        ClientRequest::MarkRead(request) => {
+            let receipt = state.services.message.mark_read(session.user_id()?, request.message_id).await?;
+            if let Some(receipt) = &receipt {
+                let participants = state.services.message.get_chat_participants(receipt.chat_id).await?;
+                state.services.websocket.broadcast_receipt(true, receipt, &participants, session.connection_id).await?;
+            }
+            serde_json::to_value(&receipt)?
+        }
+        ClientRequest::MarkDelivered(request) => {
+            let receipt = state.services.message.mark_delivered(session.user_id()?, request.message_id).await?;
+            if let Some(receipt) = &receipt {
+                let participants = state.services.message.get_chat_participants(receipt.chat_id).await?;
+                state.services.websocket.broadcast_receipt(false, receipt, &participants, session.connection_id).await?;
             }
+            serde_json::to_value(&receipt)?
+        }
+        ClientRequest::SetPresence(request) => {
+            let uid = session.user_id()?;
+            state.services.user.update_presence(uid, request).await?;
+            serde_json::to_value(fan_out_presence(state, uid).await?)?
+        }
+    };
+
+    Ok(data)
+}
+
+/// Registers the connection for the user, replacing any previous registration of this
+/// socket, and marks them online when it's their first device.
+async fn authenticate(
+    session: &mut Session,
+    state: &AppState,
+    user_id: Uuid,
+    protocol_version: u32,
+) -> anyhow::Result<Authenticated> {
+    let user = state.services.user.get_user_by_id(user_id).await?;
+
+    // Re-authenticating replaces this socket's previous registration
+    stop_all_typing(session, state).await;
+    if let (Some(previous_uid), Some(previous_cid)) = (session.user_id, session.connection_id.take()) {
+        disconnect(state, previous_uid, previous_cid).await;
+    }
+
+    let connection = register_connection(state, user_id, session.queue.clone()).await?;
+    session.user_id = Some(user_id);
+    session.username = user.username;
+    session.connection_id = Some(connection.id);
+    session.protocol_version = protocol_version.min(PROTOCOL_VERSION);
+    session.announce_online = connection.first;
+
+    info!("User {} authenticated via WebSocket on connection {}", user_id, connection.id);
+
+    Ok(Authenticated {
+        user_id,
+        connection_id: connection.id,
+        protocol_version: session.protocol_version,
+        last_seq: state.services.websocket.current_seq(user_id).await?,
+    })
+}
+
+/// Registers a queue to receive the user's events and marks them online when it's their
+/// first connection. Announcing that is left to the caller, once it has queued its first
+/// frames.
+pub async fn register_connection(
+    state: &AppState,
+    user_id: Uuid,
+    queue: OutboundQueue,
+) -> anyhow::Result<UserConnection> {
+    let connection = state.services.websocket.add_connection(user_id, queue).await;
+    if connection.first {
+        if let Err(e) = state.services.user.update_online_status(user_id, true).await {
+            disconnect(state, user_id, connection.id).await;
+            return Err(e);
         }
-        _ => {
-            warn!("Unknown WebSocket message type: {}", ws_message.message_type);
+    }
+    Ok(connection)
+}
+
+/// Relays that the user is typing. Repeated frames only extend the indicator, and are
+/// relayed again at most once per `WS_TYPING_THROTTLE` so receivers can keep it alive.
+async fn start_typing(session: &mut Session, state: &AppState, user_id: Uuid, chat_id: Uuid) -> anyhow::Result<()> {
+    let now = Instant::now();
+    let expires_at = now + Duration::from_secs(state.config.ws_typing_timeout);
+    if let Some(typing) = session.typing.get_mut(&chat_id) {
+        typing.expires_at = expires_at;
+        if now.duration_since(typing.relayed_at) < Duration::from_secs(state.config.ws_typing_throttle) {
+            return Ok(());
         }
     }
 
+    // Checked again on every relay in case the user has left the chat since
+    let conversation = match state.services.message.authorize_chat(user_id, chat_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => {
+            session.typing.remove(&chat_id);
+            return Err(e);
+        }
+    };
+    let participants = state.services.message.get_chat_participants(conversation.id).await?;
+    let typing = TypingIndicator {
+        chat_id: conversation.id,
+        user_id,
+        username: session.username.clone(),
+        is_typing: true,
+    };
+    state.services.websocket.broadcast_typing(&typing, &participants).await?;
+
+    session.typing.insert(
+        chat_id,
+        TypingState {
+            chat_id: conversation.id,
+            participants,
+            relayed_at: now,
+            expires_at,
+        },
+    );
     Ok(())
 }
+
+/// Clears the user's typing indicator in a chat, if it's shown.
+async fn stop_typing(session: &mut Session, state: &AppState, chat_id: Uuid) {
+    let (Some(typing), Some(user_id)) = (session.typing.remove(&chat_id), session.user_id) else {
+        return;
+    };
+
+    let indicator = TypingIndicator {
+        chat_id: typing.chat_id,
+        user_id,
+        username: session.username.clone(),
+        is_typing: false,
+    };
+    if let Err(e) = state.services.websocket.broadcast_typing(&indicator, &typing.participants).await {
+        warn!("Failed to clear typing indicator of user {}: {}", user_id, e);
+    }
+}
+
+/// Clears typing indicators that weren't refreshed within `WS_TYPING_TIMEOUT`.
+async fn expire_typing(session: &mut Session, state: &AppState) {
+    let now = Instant::now();
+    let expired: Vec<Uuid> = session
+        .typing
+        .iter()
+        .filter(|(_, typing)| typing.expires_at <= now)
+        .map(|(chat_id, _)| *chat_id)
+        .collect();
+    for chat_id in expired {
+        stop_typing(session, state, chat_id).await;
+    }
+}
+
+async fn stop_all_typing(session: &mut Session, state: &AppState) {
+    let chat_ids: Vec<Uuid> = session.typing.keys().copied().collect();
+    for chat_id in chat_ids {
+        stop_typing(session, state, chat_id).await;
+    }
+}
+
+/// Delivers a newly sent message to its chat. Thread replies go only to the thread's
+/// followers; the rest of the chat gets a `thread_updated` summary.
+pub async fn fan_out_message(state: &AppState, message: &MessageResponse) -> anyhow::Result<()> {
+    let participants = state.services.message.get_chat_participants(message.chat_id).await?;
+
+    match message.thread_root_id {
+        Some(root_id) => {
+            let followers = state.services.message.get_thread_followers(root_id).await?;
+            let update = state.services.message.get_thread_update(root_id).await?;
+            state.services.websocket.broadcast_thread_reply(message, &update, &followers, &participants).await
+        }
+        None => state.services.websocket.broadcast_message(message, &participants).await,
+    }
+}
+
+/// Sends a user's presence to their friends and group co-members, and to their own
+/// devices, which alone see it when the user is invisible. Returns the own-device view.
+pub async fn fan_out_presence(state: &AppState, user_id: Uuid) -> anyhow::Result<UserStatus> {
+    let user = state.services.user.get_user(user_id).await?;
+    let audience = state.services.user.get_presence_audience(user_id).await?;
+
+    state.services.websocket.broadcast(ServerEvent::UserStatus(UserStatus::visible(&user)), &audience).await?;
+
+    let own = UserStatus::own(&user);
+    state.services.websocket.broadcast(ServerEvent::UserStatus(own.clone()), &[user_id]).await?;
+
+    Ok(own)
+}
+
+fn error_reply(request_id: Option<&str>, request_type: Option<String>, err: anyhow::Error) -> Vec<String> {
+    let reply = ServerFrame {
+        request_id,
+        event: ServerEvent::Error(error_frame(request_type, err)),
+    };
+    serde_json::to_string(&reply).into_iter().collect()
+}
+
+fn error_frame(request_type: Option<String>, err: anyhow::Error) -> ErrorFrame {
+    let (code, status) = match err.downcast_ref::<ProtocolError>() {
+        Some(ProtocolError::InvalidFrame(_)) => ("invalid_frame", 400),
+        Some(ProtocolError::Unauthenticated) => ("unauthenticated", 401),
+        Some(ProtocolError::InvalidToken) => ("invalid_token", 401),
+        Some(ProtocolError::UnsupportedVersion(_)) => ("unsupported_protocol_version", 400),
+        Some(ProtocolError::RateLimited(_)) => ("rate_limited", 429),
+        None => match (err.downcast_ref::<MessageError>(), err.downcast_ref::<UserError>()) {
+            (Some(message_err), _) => (message_err.code(), message_error_status(message_err).as_u16()),
+            (None, Some(user_err)) => (user_err.code(), user_error_status(user_err).as_u16()),
+            (None, None) => {
+                error!("WebSocket request failed: {}", err);
+                return ErrorFrame {
+                    request_type,
+                    code: "internal_error",
+                    status: 500,
+                    error: "Internal server error".to_string(),
+                    retry_after_ms: None,
+                };
+            }
+        },
+    };
+
+    let retry_after_ms = match err.downcast_ref::<ProtocolError>() {
+        Some(ProtocolError::RateLimited(retry_after)) => Some(retry_after.as_millis().max(1) as u64),
+        _ => None,
+    };
+
+    ErrorFrame {
+        request_type,
+        code,
+        status,
+        error: err.to_string(),
+        retry_after_ms,
+    }
+}